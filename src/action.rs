@@ -1,4 +1,5 @@
-use crate::state::{State};
+use crate::state::{State, MessageStatus};
+use crate::message::MessageId;
 use message_io::node::NodeHandler;
 use std::time::Duration;
 use message_io::network::Endpoint;
@@ -11,4 +12,35 @@ pub enum Processing {
 
 pub trait Action: Send {
     fn process(&mut self, state: &mut State, handler: NodeHandler<Event>, server: Endpoint) -> Processing;
+
+    /// Called once when the user enters the input of the action, before processing it.
+    /// The processing can wait for the connection meanwhile other inputs are entered.
+    fn submitted(&mut self, _state: &mut State) {}
+
+    /// Checked before each processing, a cancelled action is stopped with `cancel` instead.
+    fn is_cancelled(&self, _state: &State) -> bool {
+        false
+    }
+
+    /// Last processing of a cancelled action, to tell the other side.
+    fn cancel(&mut self, _state: &mut State, _handler: NodeHandler<Event>, _server: Endpoint) {}
+}
+
+/// Flags a sent message as failed if the server did not acknowledge it in time.
+pub struct AckTimeout(MessageId);
+
+impl AckTimeout {
+    const DELAY: Duration = Duration::from_secs(10);
+
+    pub fn schedule(handler: &NodeHandler<Event>, id: MessageId) {
+        handler.signals().send_with_timer(Event::Action(Box::new(AckTimeout(id))), Self::DELAY);
+    }
+}
+
+impl Action for AckTimeout {
+    fn process(&mut self, state: &mut State, _: NodeHandler<Event>, _: Endpoint) -> Processing {
+        // Only a message still sending can fail, acknowledged ones are not updated.
+        state.update_message_status(self.0, MessageStatus::Failed);
+        Processing::Completed
+    }
 }
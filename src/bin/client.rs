@@ -1,25 +1,60 @@
-use termchat_wan::state::{State, CursorMovement, ChatMessage, MessageType, ScrollMovement};
+use termchat_wan::state::{
+    State, ConnectionStatus, CursorMovement, ChatMessage, MessageStatus, MessageType, OfferStatus, ScrollMovement,
+    TransferDirection, TransferStatus,
+};
 use termchat_wan::{terminal_events::{TerminalEventCollector},};
 use termchat_wan::renderer::{Renderer};
-use termchat_wan::action::{Action, Processing};
-use termchat_wan::message::{NetMessage, Chunk};
-use termchat_wan::util::{Result, Reportable};
+use termchat_wan::action::{Action, AckTimeout, Processing};
+use termchat_wan::message::{
+    Capabilities, NetMessage, Chunk, Credential, Receipt, DeliveryStatus, HEARTBEAT_INTERVAL, MAX_SEALED_LEN, MAX_SERVER_FRAME,
+    MAX_STRING_LEN, PROTOCOL_VERSION, TransferId,
+};
+use termchat_wan::util::{self, Result, Reportable};
 use termchat_wan::commands::{CommandManager};
 use termchat_wan::commands::send_file::SendFileCommand;
 use termchat_wan::commands::whisper_message::SendWhisperCommand;
+use termchat_wan::commands::room::{JoinRoomCommand, LeaveRoomCommand, ListRoomsCommand};
+use termchat_wan::commands::nick::NickCommand;
+use termchat_wan::commands::trust::TrustCommand;
+use termchat_wan::commands::ping::PingCommand;
+use termchat_wan::commands::who::WhoCommand;
+use termchat_wan::commands::offer::{AcceptCommand, RejectCommand};
+use termchat_wan::commands::transfers::{CancelCommand, PauseCommand, ResumeCommand, TransfersCommand};
+use termchat_wan::e2e::{self, KeyRing};
+use termchat_wan::last_seen::LastSeen;
 use termchat_wan::config::Config;
+use termchat_wan::tls;
+use termchat_wan::transfer::{FileDigest, IncomingFile, Manifest};
+use termchat_wan::compression;
 use termchat_wan::events::Event;
 use crossterm::event::{Event as TermEvent, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
 use message_io::node::{self, NodeEvent, NodeHandler, NodeListener};
 use message_io::network::{NetEvent, Endpoint, Transport};
-use std::time::Duration;
-use std::io::{ErrorKind};
+use std::time::{Duration, Instant};
+use std::collections::{HashMap};
 use clap::{App, Arg};
 use std::net::{SocketAddrV4};
 
 pub struct Application<'a> {
     config: &'a Config,
+    credential: Credential,
     state: State,
+    // Files being received, by sender and transfer id.
+    incoming: HashMap<(String, TransferId), IncomingFile>,
+    last_seen: LastSeen,
+    // Between joining a room and receiving its history, the live messages are already in the history.
+    awaiting_history: bool,
+    // Connection with the server, `None` while disconnected.
+    server: Option<Endpoint>,
+    // Last time the server sent something.
+    last_heard: Instant,
+    reconnect_delay: Duration,
+    // Name used to log on again after a reconnection, the last one accepted by the server.
+    logon_name: String,
+    // Room to join again after a reconnection.
+    rejoin_room: Option<String>,
+    // Messages written while not logged on, sent at the next logon.
+    outbox: Vec<NetMessage>,
     commands: CommandManager,
     handler: NodeHandler<Event>,
     listener: Option<NodeListener<Event>>,
@@ -27,24 +62,58 @@ pub struct Application<'a> {
 }
 
 impl<'a> Application<'a> {
+    const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+    const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+    // Heartbeats answered by the server that can be missed before closing the connection.
+    const MISSED_BEATS: u32 = 3;
+    // Actions are paused while disconnected, they are retried with this delay.
+    const OFFLINE_ACTION_DELAY: Duration = Duration::from_millis(500);
 
+    pub fn new(config: &'a Config, credential: Credential) -> Result<Application<'a>> {
 
-    pub fn new(config: &'a Config) -> Result<Application<'a>> {
-
-        let commands = CommandManager::default().with(SendFileCommand).with(SendWhisperCommand);
+        let commands = CommandManager::default()
+            .with(SendFileCommand)
+            .with(SendWhisperCommand)
+            .with(JoinRoomCommand)
+            .with(LeaveRoomCommand)
+            .with(ListRoomsCommand)
+            .with(NickCommand::new(credential.clone()))
+            .with(TrustCommand)
+            .with(PingCommand)
+            .with(WhoCommand)
+            .with(AcceptCommand)
+            .with(RejectCommand)
+            .with(TransfersCommand)
+            .with(CancelCommand)
+            .with(PauseCommand)
+            .with(ResumeCommand);
         let (handler, listener) = node::split::<Event>();
         let h = handler.clone();
         let _terminal_events = TerminalEventCollector::new(move |term_event| match term_event {
             Ok(event) => h.signals().send(Event::Terminal(event)),
             Err(e) => h.signals().send(Event::Close(Some(e))),
         })?;
-        
+
+        let mut state = State::default();
+        state.set_key_ring(KeyRing::load(Config::dir().as_deref())?);
+        let last_seen = LastSeen::load(Config::dir().as_deref())?;
+
         Ok(Application {
             config,
-            state: State::default(),
+            credential,
+            state,
+            incoming: HashMap::new(),
+            last_seen,
+            awaiting_history: false,
+            server: None,
+            last_heard: Instant::now(),
+            reconnect_delay: Self::MIN_RECONNECT_DELAY,
+            logon_name: config.user_name.clone(),
+            rejoin_room: None,
+            outbox: Vec::new(),
             commands,
             // Stored because we need its internal thread running until the Application was dropped,
-            handler: handler,
+            handler,
             listener: Some(listener),
             _terminal_events,
         })
@@ -54,122 +123,569 @@ impl<'a> Application<'a> {
         let mut renderer = Renderer::new(out)?;
         renderer.render(&self.state, &self.config.theme)?;
 
-        let (server, _) = self.handler.network().connect(Transport::FramedTcp, self.config.server_addr)?;
+        self.connect();
+        self.handler.signals().send_with_timer(Event::Heartbeat, HEARTBEAT_INTERVAL);
 
+        // Reason of closing the application, printed once the terminal is restored.
+        let mut close_error = None;
+        let close_error_ref = &mut close_error;
         self.listener.take().unwrap().for_each(move |event| match event {
             NodeEvent::Network(net_event) => match net_event {
-                NetEvent::Connected(_endpoint, _ok) => {
-                    let message = NetMessage::Hi(self.config.user_name.clone());
-                    let payload = bincode::serialize(&message).unwrap();
-                    self.handler.network().send(server, payload.as_ref());
-
-                    self.handler.signals().send_with_timer(Event::Network(NetMessage::BBB(self.config.user_name.clone())), Duration::from_secs(3));
+                NetEvent::Connected(endpoint, ok) => {
+                    if ok {
+                        self.server = Some(endpoint);
+                        self.last_heard = Instant::now();
+                        self.state.set_connection(ConnectionStatus::Online);
+                        self.state.set_capabilities(None);
+                        self.send_to_server(&NetMessage::Handshake(PROTOCOL_VERSION, Capabilities::ALL));
+                        let message = NetMessage::Logon(self.logon_name.clone(), self.credential.clone());
+                        self.send_to_server(&message);
+                        self.send_to_server(&NetMessage::BBB(util::timestamp_millis()));
+                    }
+                    else {
+                        self.connection_lost(format!("Unable to connect to {}", self.config.server_addr));
+                    }
+                    renderer.render(&self.state, &self.config.theme).unwrap();
                 },
                 NetEvent::Accepted(_, _) => unreachable!(),
-                NetEvent::Message(_endpoint, data) => {
-                    let message: NetMessage = bincode::deserialize(data).unwrap();
-                    self.handler.signals().send(Event::Network(message));
+                NetEvent::Message(endpoint, data) => {
+                    self.last_heard = Instant::now();
+                    match NetMessage::decode(data, MAX_SERVER_FRAME) {
+                        Ok(message) => self.handler.signals().send(Event::Network(message)),
+                        Err(e) => {
+                            // Reconnecting gets the server back to a known state.
+                            self.handler.network().remove(endpoint.resource_id());
+                            self.connection_lost(format!("Invalid message from the server: {}", e));
+                            renderer.render(&self.state, &self.config.theme).unwrap();
+                        }
+                    }
+                },
+                NetEvent::Disconnected(_endpoint) => {
+                    self.connection_lost(String::from("Connection with the server lost"));
+                    renderer.render(&self.state, &self.config.theme).unwrap();
                 },
-                NetEvent::Disconnected(_endpoint) => (),
             },
             NodeEvent::Signal(msg) => {
                 
                 match msg {
                     Event::Network(net_message) => {
-                        self.process_network_message(net_message, &server);
+                        self.process_network_message(net_message);
                     },
                     Event::Terminal(term_event) => {
-                        self.process_terminal_event(term_event, &server);
+                        self.process_terminal_event(term_event);
                     },
                     Event::Action(action) => {
-                        self.process_action(action, self.handler.clone(), server);
+                        self.process_action(action);
+                        self.drop_cancelled_incoming();
                     },
-                    Event::Close(_) => {
+                    Event::Connect => {
+                        if !matches!(self.state.connection(), ConnectionStatus::Closed) {
+                            self.connect();
+                        }
+                    },
+                    Event::Heartbeat => {
+                        self.heartbeat();
+                        self.state.expire_offers();
+                        self.handler.signals().send_with_timer(Event::Heartbeat, HEARTBEAT_INTERVAL);
+                    },
+                    Event::Close(error) => {
+                        self.last_seen.save().report_if_err(&mut self.state);
+                        *close_error_ref = error;
                         self.handler.stop();
                     }
                 }
                 renderer.render(&self.state, &self.config.theme).unwrap();
             }
         });
-        Ok(())
+        match close_error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Opens a new connection with the server, the result arrives as a `NetEvent::Connected`.
+    fn connect(&mut self) {
+        self.state.set_connection(ConnectionStatus::Connecting);
+        let server_addr = match self.config.tls_trust() {
+            Some(trust) => tls::connect(self.config.server_addr.into(), &self.config.tls_server_name(), &trust),
+            None => Ok(self.config.server_addr.into()),
+        };
+        let connected = server_addr.and_then(|addr| Ok(self.handler.network().connect(Transport::FramedTcp, addr)?));
+        if let Err(e) = connected {
+            self.connection_lost(format!("Unable to connect to {}: {}", self.config.server_addr, e));
+        }
+    }
+
+    /// Schedules a new connection, waiting twice as long as the previous time.
+    fn connection_lost(&mut self, reason: String) {
+        if let ConnectionStatus::Offline(_) | ConnectionStatus::Closed = self.state.connection() {
+            return
+        }
+        // Only the first failure is reported, not each retry.
+        if self.server.take().is_some() || self.reconnect_delay == Self::MIN_RECONNECT_DELAY {
+            reason.report_err(&mut self.state);
+        }
+        if self.state.user_name().is_some() {
+            self.rejoin_room = self.state.room().map(String::from);
+        }
+        self.state.set_user_name(None);
+        self.state.set_latency(None);
+        self.state.set_roster(Vec::new());
+        self.awaiting_history = false;
+
+        let delay = self.reconnect_delay;
+        self.reconnect_delay = (delay * 2).min(Self::MAX_RECONNECT_DELAY);
+        self.state.set_connection(ConnectionStatus::Offline(delay));
+        self.handler.signals().send_with_timer(Event::Connect, delay);
+    }
+
+    /// Sends a heartbeat, or closes the connection if the server stopped answering them.
+    fn heartbeat(&mut self) {
+        if let Some(server) = self.server {
+            if self.last_heard.elapsed() > HEARTBEAT_INTERVAL * Self::MISSED_BEATS {
+                self.handler.network().remove(server.resource_id());
+                self.connection_lost(String::from("The server stopped answering"));
+            }
+            else {
+                self.send_to_server(&NetMessage::BBB(util::timestamp_millis()));
+            }
+        }
+    }
+
+    fn send_to_server(&self, message: &NetMessage) {
+        if let Some(server) = self.server {
+            let payload = message.encode(self.has_capability(Capabilities::COMPRESSION));
+            self.handler.network().send(server, payload.as_ref());
+        }
+    }
+
+    fn cancel_selected_transfer(&mut self) {
+        let transfer = match self.state.selected_transfer() {
+            Some(transfer) => transfer,
+            _ => return,
+        };
+        let (id, file_name) = (transfer.id, transfer.file_name.clone());
+        match self.state.control_transfer(id, TransferStatus::Cancelled) {
+            Ok(()) => {
+                self.drop_cancelled_incoming();
+                format!("Transfer of '{}' cancelled", file_name).report_info(&mut self.state);
+            }
+            Err(e) => e.report_err(&mut self.state),
+        }
+    }
+
+    /// Removes the files being received whose transfer was cancelled, the senders are told to stop.
+    fn drop_cancelled_incoming(&mut self) {
+        let state = &self.state;
+        let cancelled: Vec<_> = self
+            .incoming
+            .keys()
+            .filter(|(from, transfer_id)| {
+                state.find_transfer(TransferDirection::Receiving, from, *transfer_id).is_none()
+            })
+            .cloned()
+            .collect();
+        for (from, transfer_id) in cancelled {
+            self.incoming.remove(&(from.clone(), transfer_id));
+            self.send_to_server(&NetMessage::SendReceipt(from, transfer_id, Receipt::Cancelled));
+        }
     }
 
-    fn process_network_message(&mut self, message: NetMessage, server: &Endpoint) {
+    /// Ends the entry of the transfers panel of a file being received.
+    fn finish_incoming(&mut self, from: &str, transfer_id: TransferId, status: TransferStatus) {
+        if let Some(id) = self.state.find_transfer(TransferDirection::Receiving, from, transfer_id) {
+            if status == TransferStatus::Completed {
+                let size = self.state.transfer(id).size;
+                self.state.update_transfer(id, size);
+            }
+            self.state.set_transfer_status(id, status);
+        }
+    }
+
+    /// Writes a chunk of a file sent by another user and tells the sender how far the file was received.
+    fn receive_chunk(&mut self, from: String, transfer_id: TransferId, chunk: Chunk) {
+        let transfer = (from.clone(), transfer_id);
+        // The name of the files is only known from their sealed offer.
+        let mut file_name = match self.incoming.get(&transfer) {
+            Some(file) => Some(file.offer().name()),
+            None => self.state.incoming_name(&from, transfer_id),
+        }
+        .unwrap_or_default();
+        let receipt = match chunk {
+            Chunk::Cancel => {
+                let receiving = self.incoming.remove(&transfer).is_some();
+                let offered = self.state.withdraw_offer(&from, transfer_id);
+                self.finish_incoming(&from, transfer_id, TransferStatus::Cancelled);
+                if receiving || offered {
+                    format!("'{}' cancelled the transfer of '{}'", from, file_name).report_warn(&mut self.state);
+                }
+                return
+            }
+            Chunk::Error => {
+                self.incoming.remove(&transfer);
+                self.finish_incoming(&from, transfer_id, TransferStatus::Failed(String::from("error of the sender")));
+                format!("'{}' had an error while sending '{}'", from, file_name).report_err(&mut self.state);
+                return
+            }
+            Chunk::Dropped => {
+                self.incoming.remove(&transfer);
+                self.finish_incoming(&from, transfer_id, TransferStatus::Failed(String::from("dropped by the server")));
+                format!("The server dropped the transfer of '{}' from '{}', it was not received fast enough", file_name, from)
+                    .report_err(&mut self.state);
+                return
+            }
+            Chunk::Offer(sealed) => {
+                let (manifest, warning) = self.state.key_ring_mut().open(&from, &sealed);
+                if let Some(warning) = warning {
+                    warning.report_warn(&mut self.state);
+                }
+                let manifest = match manifest.and_then(|manifest| Manifest::from_bytes(&manifest)) {
+                    Ok(manifest) => manifest,
+                    Err(e) => {
+                        format!("Invalid offer from '{}': {}", from, e).report_err(&mut self.state);
+                        return
+                    }
+                };
+                self.state.add_offer(from.clone(), transfer_id, manifest);
+                if !self.config.accept_files_from.contains(&from) {
+                    self.righ_the_bell();
+                    return
+                }
+                self.state.set_offer_status(&from, transfer_id, OfferStatus::AutoAccepted);
+                Receipt::Accepted(Capabilities::ALL)
+            }
+            // The next chunks of a failed transfer, the sender already knows.
+            Chunk::Data(offset, _) if offset > 0 && !self.incoming.contains_key(&transfer) => {
+                Receipt::Failed(String::from("the start of the file was not received"))
+            }
+            Chunk::Data(offset, sealed) => {
+                // The transfer starts after accepting the offer, or again from the beginning.
+                let offer = match offset {
+                    0 => {
+                        let previous = self.incoming.remove(&transfer).map(|file| file.offer().clone());
+                        match self.state.take_accepted_offer(&from, transfer_id).or(previous) {
+                            Some(offer) => {
+                                file_name = offer.name();
+                                Some(offer)
+                            }
+                            None => {
+                                let receipt = Receipt::Failed(String::from("the file was not accepted"));
+                                self.send_to_server(&NetMessage::SendReceipt(from, transfer_id, receipt));
+                                return
+                            }
+                        }
+                    }
+                    _ => None,
+                };
+                let (data, warning) = self.state.key_ring_mut().open(&from, &sealed);
+                if let Some(warning) = warning {
+                    warning.report_warn(&mut self.state);
+                }
+                let download_dir = self.config.download_dir();
+                let try_write = |incoming: &mut HashMap<_, IncomingFile>| -> Result<(u64, u64, u64)> {
+                    let packed = data?;
+                    let data = compression::unpack_chunk(&packed, MAX_SEALED_LEN)?;
+                    if let Some(offer) = offer {
+                        let file = IncomingFile::create(&download_dir, &from, transfer_id, offer)?;
+                        incoming.insert(transfer.clone(), file);
+                    }
+                    let file = incoming.get_mut(&transfer).unwrap();
+                    file.write(offset, &data)?;
+                    Ok((file.received(), data.len() as u64, packed.len() as u64))
+                };
+                match try_write(&mut self.incoming) {
+                    Ok((received, raw, packed)) => {
+                        let size = self.incoming[&transfer].offer().digest.size;
+                        let id = match self.state.find_transfer(TransferDirection::Receiving, &from, transfer_id) {
+                            Some(id) => id,
+                            None => self.state.add_transfer(TransferDirection::Receiving, &from, transfer_id, &file_name, size),
+                        };
+                        self.state.count_transfer_data(id, raw, packed);
+                        self.state.update_transfer(id, received);
+                        Receipt::Received(received)
+                    }
+                    Err(e) => {
+                        self.incoming.remove(&transfer);
+                        self.finish_incoming(&from, transfer_id, TransferStatus::Failed(e.to_string()));
+                        format!("Unable to receive '{}' from '{}': {}", file_name, from, e).report_err(&mut self.state);
+                        Receipt::Failed(e.to_string())
+                    }
+                }
+            }
+            Chunk::End(sealed) => {
+                let (digest, warning) = self.state.key_ring_mut().open(&from, &sealed);
+                if let Some(warning) = warning {
+                    warning.report_warn(&mut self.state);
+                }
+                let started = match self.incoming.contains_key(&transfer) {
+                    true => Ok(()),
+                    // Without any data, the transfer of empty files starts with its end.
+                    false => match self.state.take_accepted_offer(&from, transfer_id).filter(|offer| offer.digest.size == 0) {
+                        Some(offer) => {
+                            file_name = offer.name();
+                            IncomingFile::create(&self.config.download_dir(), &from, transfer_id, offer).map(|file| {
+                                self.state.add_transfer(TransferDirection::Receiving, &from, transfer_id, &file_name, 0);
+                                self.incoming.insert(transfer.clone(), file);
+                            })
+                        }
+                        None => Err("the file was not received".into()),
+                    },
+                };
+                let verified = started.and_then(|_| {
+                    let digest = FileDigest::from_bytes(&digest?)?;
+                    self.incoming[&transfer].verify(&digest)
+                });
+                let completed = verified.and_then(|verified| match verified {
+                    true => self.incoming.remove(&transfer).unwrap().complete().map(Some),
+                    false => Ok(None),
+                });
+                match completed {
+                    Ok(Some(paths)) => {
+                        let compression = self
+                            .state
+                            .find_transfer(TransferDirection::Receiving, &from, transfer_id)
+                            .and_then(|id| self.state.transfer(id).compression_ratio())
+                            .map(|ratio| format!(" (compressed to {:.0}%)", ratio * 100.0))
+                            .unwrap_or_default();
+                        self.finish_incoming(&from, transfer_id, TransferStatus::Completed);
+                        let paths = paths.iter().map(|path| format!("'{}'", path.display())).collect::<Vec<_>>();
+                        format!("Successfully received {} from user '{}'!{}", paths.join(", "), from, compression)
+                            .report_info(&mut self.state);
+                        self.righ_the_bell();
+                        Receipt::Completed
+                    }
+                    // Chunks were lost, the sender sends them again.
+                    Ok(None) => Receipt::Received(self.incoming[&transfer].received()),
+                    Err(e) => {
+                        self.incoming.remove(&transfer);
+                        self.finish_incoming(&from, transfer_id, TransferStatus::Failed(e.to_string()));
+                        format!("Unable to receive '{}' from '{}': {}", file_name, from, e).report_err(&mut self.state);
+                        Receipt::Failed(e.to_string())
+                    }
+                }
+            }
+        };
+        self.send_to_server(&NetMessage::SendReceipt(from, transfer_id, receipt));
+    }
+
+    fn process_network_message(&mut self, message: NetMessage) {
         match message {
-            NetMessage::BBB(user) =>{
-                let u = user.clone();
-                let msg = NetMessage::BBB(user.into());
-                let payload = bincode::serialize(&msg).unwrap();
-                self.handler.network().send(*server, payload.as_ref());
-                self.handler.signals().send_with_timer(Event::Network(NetMessage::BBB(u.into())), Duration::from_secs(10));
+            NetMessage::Handshake(_version, capabilities) => {
+                self.state.set_capabilities(Some(capabilities));
+            }
+            NetMessage::Incompatible(reason) => {
+                self.state.set_connection(ConnectionStatus::Closed);
+                let error = format!("Incompatible server: {}", reason);
+                self.handler.signals().send_with_priority(Event::Close(Some(error.into())));
+            }
+            NetMessage::BBB(timestamp) => {
+                let latency = util::timestamp_millis().saturating_sub(timestamp);
+                self.state.set_latency(Some(Duration::from_millis(latency)));
+            },
+            NetMessage::UserPing(from, timestamp) => {
+                self.send_to_server(&NetMessage::SendPong(from, timestamp));
+            },
+            NetMessage::UserPong(from, timestamp) => {
+                let latency = util::timestamp_millis().saturating_sub(timestamp);
+                format!("Latency with '{}': {} ms", from, latency).report_info(&mut self.state);
+            },
+            NetMessage::LogonAccepted(user) => {
+                format!("Logged on as '{}'", user).report_info(&mut self.state);
+                self.reconnect_delay = Self::MIN_RECONNECT_DELAY;
+                self.logon_name = user.clone();
+                self.state.set_user_name(Some(user));
+                self.send_to_server(&NetMessage::RequestRoster);
+                if let Some(key) = self.state.key_ring_mut().public_key() {
+                    format!("Your key fingerprint for private messages is {}", e2e::fingerprint(&key)).report_info(&mut self.state);
+                    self.send_to_server(&NetMessage::PublishKey(key));
+                }
+                for message in std::mem::take(&mut self.outbox) {
+                    self.send_to_server(&message);
+                    if let NetMessage::SendMessage(id, _) = message {
+                        AckTimeout::schedule(&self.handler, id);
+                    }
+                }
+            },
+            NetMessage::UserKey(user, key) => {
+                if let Some(warning) = self.state.key_ring_mut().see_key(&user, key) {
+                    warning.report_warn(&mut self.state);
+                }
+            },
+            NetMessage::LogonRejected(reason) => {
+                self.state.set_user_name(None);
+                format!("Logon rejected: {}", reason).report_err(&mut self.state);
+                String::from("Use '?nick <name>' to log on with another name").report_info(&mut self.state);
+            },
+            NetMessage::NickRejected(reason) => {
+                format!("Name change rejected: {}", reason).report_err(&mut self.state);
+            },
+            NetMessage::Renamed(old, new) => {
+                self.state.roster_remove(&old);
+                self.state.roster_add(new.clone());
+                if self.is_me(&old) {
+                    format!("You are now known as '{}'", new).report_info(&mut self.state);
+                    self.logon_name = new.clone();
+                    self.state.set_user_name(Some(new));
+                }
+                else {
+                    format!("'{}' is now known as '{}'", old, new).report_info(&mut self.state);
+                    self.state.key_ring_mut().forget(&old);
+                }
             },
             NetMessage::Hi(user) => {
-                if user != self.config.user_name {
-                    self.state.add_message(ChatMessage::new(user.into(), MessageType::Connection));
+                self.state.roster_add(user.clone());
+                if !self.is_me(&user) {
+                    self.state.add_message(ChatMessage::new(user, MessageType::Connection));
                     self.righ_the_bell();
                 }
             },
             NetMessage::Bye(user) => {
-                if user != self.config.user_name {
-                    self.state.add_message(ChatMessage::new(user.into(), MessageType::Disconnection));
+                self.state.roster_remove(&user);
+                self.state.key_ring_mut().forget(&user);
+                if !self.is_me(&user) {
+                    self.state.add_message(ChatMessage::new(user, MessageType::Disconnection));
                     self.righ_the_bell();
                 }
             },
-            NetMessage::UserMessage(user, content) => {
-                if user != self.config.user_name {
-                    self.state.add_message(ChatMessage::new(user.into(), MessageType::Text(content)));
-                    self.righ_the_bell();
+            NetMessage::UserMessage(id, user, content) => {
+                if self.awaiting_history {
+                    return
                 }
-            }
-            NetMessage::WhisperMessage(from, to, content) => {
-                if to == self.config.user_name {
-                    self.state.add_message(ChatMessage::new(from.into(), MessageType::PrivateText(content)));
+                if let Some(room) = self.state.room() {
+                    self.last_seen.update(&self.config.server_addr.to_string(), room, id);
+                }
+                if !self.is_me(&user) {
+                    self.state.add_message(ChatMessage::new(user, MessageType::Text(content)));
                     self.righ_the_bell();
                 }
             }
-            NetMessage::UserData(from, _to, file_name, chunk) => {
-                use std::io::Write;
-                    match chunk {
-                        Chunk::Error => {
-                            format!("'{}' had an error while sending '{}'", from, file_name)
-                                .report_err(&mut self.state);
-                        }
-                        Chunk::End => {
-                            format!(
-                                "Successfully received file '{}' from user '{}'!",
-                                std::env::temp_dir().join("termchat").join(&from).join(file_name).to_str().unwrap(),
-                                 from
-                            )
-                            .report_info(&mut self.state);
+            NetMessage::WhisperMessage(id, from, to, sealed) => {
+                if self.is_me(&to) {
+                    let (content, warning) = self.state.key_ring_mut().open(&from, &sealed);
+                    if let Some(warning) = warning {
+                        warning.report_warn(&mut self.state);
+                    }
+                    match content.and_then(|content| Ok(String::from_utf8(content)?)) {
+                        Ok(content) => {
+                            self.send_to_server(&NetMessage::ConfirmDelivery(from.clone(), id));
+                            self.state.add_message(ChatMessage::new(from, MessageType::PrivateText(content)));
                             self.righ_the_bell();
                         }
-                        Chunk::Data(data) => {
-                            let try_write = || -> Result<()> {
-                                let user_path = std::env::temp_dir().join("termchat").join(&from);
-                                match std::fs::create_dir_all(&user_path) {
-                                    Ok(_) => (),
-                                    Err(ref err) if err.kind() == ErrorKind::AlreadyExists => (),
-                                    Err(e) => return Err(e.into()),
-                                }
-
-                                let file_path = user_path.join(file_name);
-                                std::fs::OpenOptions::new()
-                                    .create(true)
-                                    .append(true)
-                                    .open(file_path)?
-                                    .write_all(&data)?;
-
-                                Ok(())
-                            };
-
-                            try_write().report_if_err(&mut self.state);
-                        }
+                        Err(e) => format!("Private message from '{}' lost: {}", from, e).report_err(&mut self.state),
+                    }
+                }
+            }
+            NetMessage::UserData(from, _to, transfer_id, chunk) => self.receive_chunk(from, transfer_id, chunk),
+            NetMessage::UserReceipt(from, transfer_id, receipt) => self.state.set_receipt(from, transfer_id, receipt),
+            NetMessage::RoomRejected(room, reason) => {
+                format!("Unable to join the room '{}': {}", room, reason).report_err(&mut self.state);
+            }
+            NetMessage::RoomJoined(room) => {
+                // After a reconnection the server puts the user in the default room.
+                if let Some(previous) = self.rejoin_room.take() {
+                    if previous != room {
+                        self.send_to_server(&NetMessage::JoinRoom(previous));
+                        return
+                    }
+                }
+                format!("You are now in room '{}'", room).report_info(&mut self.state);
+                if self.has_capability(Capabilities::HISTORY) {
+                    let since = self.last_seen.get(&self.config.server_addr.to_string(), &room);
+                    self.send_to_server(&NetMessage::RequestHistory(since));
+                    self.awaiting_history = true;
+                }
+                self.state.set_room(room);
+            }
+            NetMessage::History(room, entries) => {
+                if self.state.room() != Some(room.as_str()) {
+                    return
+                }
+                self.awaiting_history = false;
+                if let Some(last) = entries.last() {
+                    self.last_seen.update(&self.config.server_addr.to_string(), &room, last.id);
+                    for entry in entries {
+                        self.state.add_message(ChatMessage::replayed(entry));
+                    }
+                    self.state.add_message(ChatMessage::new(String::new(), MessageType::HistoryEnd));
+                }
+            }
+            NetMessage::Delivery(user, id, status) => {
+                let message_status = match status {
+                    DeliveryStatus::Queued => {
+                        format!("'{}' is offline, it will be delivered at the next logon", user)
+                            .report_info(&mut self.state);
+                        MessageStatus::Sent
+                    }
+                    DeliveryStatus::UnknownUser => {
+                        format!("The user '{}' is unknown", user).report_err(&mut self.state);
+                        MessageStatus::Failed
                     }
+                    DeliveryStatus::Rejected(reason) => {
+                        format!("Unable to deliver to '{}': {}", user, reason).report_err(&mut self.state);
+                        MessageStatus::Failed
+                    }
+                };
+                if let Some(id) = id {
+                    self.state.update_message_status(id, message_status);
+                }
+            }
+            NetMessage::Ack(id) => {
+                self.state.update_message_status(id, MessageStatus::Sent);
+            }
+            NetMessage::Delivered(_user, id) => {
+                self.state.update_message_status(id, MessageStatus::Delivered);
+            }
+            NetMessage::Roster(users) => {
+                self.state.set_roster(users);
+            }
+            NetMessage::Notice(content) => {
+                self.state.add_server_notice_message(content);
+                self.righ_the_bell();
+            }
+            NetMessage::Limits(limits) => {
+                self.state.set_rate_limits(limits);
+            }
+            NetMessage::Throttled(reason) => {
+                format!("Slow down: {}", reason).report_warn(&mut self.state);
+            }
+            NetMessage::Kicked(reason) => {
+                if let Some(server) = self.server.take() {
+                    self.handler.network().remove(server.resource_id());
+                }
+                format!("Disconnected by the server: {}", reason).report_err(&mut self.state);
+                self.state.set_user_name(None);
+                self.state.set_latency(None);
+                self.state.set_roster(Vec::new());
+                self.state.set_connection(ConnectionStatus::Closed);
             }
+            NetMessage::RoomList(rooms) => {
+                let rooms = rooms
+                    .iter()
+                    .map(|(room, members)| format!("{} ({})", room, members))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("Rooms: {}", rooms).report_info(&mut self.state);
+            }
+            NetMessage::Logon(_, _)
+            | NetMessage::Nick(_)
+            | NetMessage::SendMessage(_, _)
+            | NetMessage::PublishKey(_)
+            | NetMessage::RequestKey(_)
+            | NetMessage::SendWhisper(_, _, _)
+            | NetMessage::ConfirmDelivery(_, _)
+            | NetMessage::SendData(_, _, _)
+            | NetMessage::SendReceipt(_, _, _)
+            | NetMessage::JoinRoom(_)
+            | NetMessage::LeaveRoom
+            | NetMessage::ListRooms
+            | NetMessage::RequestHistory(_)
+            | NetMessage::RequestRoster
+            | NetMessage::SendPing(_, _)
+            | NetMessage::SendPong(_, _) => (),
         }
     }
 
-    fn process_terminal_event(&mut self, term_event: TermEvent, server: &Endpoint) {
+    fn process_terminal_event(&mut self, term_event: TermEvent) {
         match term_event {
             TermEvent::FocusGained => (),
             TermEvent::FocusLost => (),
@@ -184,33 +700,49 @@ impl<'a> Application<'a> {
                     if character == 'c' && modifiers.contains(KeyModifiers::CONTROL) {
                         self.handler.signals().send_with_priority(Event::Close(None));
                     }
+                    else if character == 'x' && modifiers.contains(KeyModifiers::CONTROL) {
+                        if kind == KeyEventKind::Press && self.state.show_transfers() {
+                            self.cancel_selected_transfer();
+                        }
+                    }
                     else {
-                        match kind {
-                            KeyEventKind::Press => {self.state.input_write(character);},
-                            _ => ()
+                        if kind == KeyEventKind::Press {
+                            self.state.input_write(character);
                         }
                     }
                 }
                 KeyCode::Enter => {
                     if let Some(input) = self.state.reset_input() {
-                        match self.commands.find_command_action(&input, self.config).transpose() {
+                        let parsed = match input.len() {
+                            len if len > MAX_STRING_LEN => {
+                                Err(format!("The message is too long, the limit is {} bytes", MAX_STRING_LEN).into())
+                            }
+                            _ => self.commands.find_command_action(&input, self.config).transpose(),
+                        };
+                        match parsed {
                             Ok(action) => {
                                 let message = ChatMessage::new(
-                                    format!("{} (me)", self.config.user_name),
+                                    format!("{} (me)", self.state.user_name().unwrap_or(&self.logon_name)),
                                     MessageType::Text(input.clone()),
                                 );
-                                self.state.add_message(message);
-                                
+                                self.state.add_input_message(message);
+
                                 match action {
-                                    Some(action) => self.process_action(action, self.handler.clone(), *server),
+                                    Some(mut action) => {
+                                        action.submitted(&mut self.state);
+                                        self.process_action(action)
+                                    }
                                     None => {
                                         if input.starts_with('?') {
                                             String::from("This command doesn't exists")
                                                 .report_err(&mut self.state);
+                                        }else if self.state.user_name().is_none() {
+                                            let id = self.state.track_input_message();
+                                            self.outbox.push(NetMessage::SendMessage(id, input.clone()));
                                         }else{
-                                            let msg = NetMessage::UserMessage(self.config.user_name.clone(), input.clone());
-                                            let payload = bincode::serialize(&msg).unwrap();
-                                            self.handler.network().send(*server, payload.as_ref());
+                                            let id = self.state.track_input_message();
+                                            self.send_to_server(&NetMessage::SendMessage(id, input.clone()));
+                                            AckTimeout::schedule(&self.handler, id);
                                         }
                                     }
                                 }
@@ -248,13 +780,40 @@ impl<'a> Application<'a> {
                 KeyCode::PageUp => {
                     self.state.messages_scroll(ScrollMovement::Start);
                 }
+                KeyCode::F(2) if kind == KeyEventKind::Press => {
+                    self.state.toggle_roster();
+                }
+                KeyCode::F(3) if kind == KeyEventKind::Press => {
+                    self.state.toggle_transfers();
+                }
+                KeyCode::Tab if kind == KeyEventKind::Press => {
+                    self.state.select_transfer(true);
+                }
+                KeyCode::BackTab if kind == KeyEventKind::Press => {
+                    self.state.select_transfer(false);
+                }
                 _ => (),
             },
         }
     }
 
-    fn process_action(&mut self, mut action: Box<dyn Action>, handler: NodeHandler<Event>, server: Endpoint) {
-        match action.process(&mut self.state, handler, server) {
+    /// Processes the action, or pauses it until the connection is recovered.
+    fn process_action(&mut self, mut action: Box<dyn Action>) {
+        // Until the handshake is answered the features of the server are unknown.
+        let server = match (self.server, self.state.capabilities()) {
+            (Some(server), Some(_)) => server,
+            // Never connected again, the pending actions are dropped.
+            _ if matches!(self.state.connection(), ConnectionStatus::Closed) => return,
+            _ => {
+                self.handler.signals().send_with_timer(Event::Action(action), Self::OFFLINE_ACTION_DELAY);
+                return
+            }
+        };
+        if action.is_cancelled(&self.state) {
+            action.cancel(&mut self.state, self.handler.clone(), server);
+            return
+        }
+        match action.process(&mut self.state, self.handler.clone(), server) {
             Processing::Completed => (),
             Processing::Partial(delay) => {
                 self.handler.signals().send_with_timer(Event::Action(action), delay);
@@ -262,6 +821,14 @@ impl<'a> Application<'a> {
         }
     }
 
+    fn has_capability(&self, capability: Capabilities) -> bool {
+        self.state.capabilities().is_some_and(|capabilities| capabilities.contains(capability))
+    }
+
+    fn is_me(&self, user: &str) -> bool {
+        self.state.user_name() == Some(user)
+    }
+
     pub fn righ_the_bell(&self) {
         if self.config.terminal_bell {
             print!("\x07");
@@ -309,13 +876,41 @@ fn main(){
                 .takes_value(true)
                 .help("Choose which theme should termchat use, values are dark and light"),
         )
+        .arg(
+            Arg::with_name("password")
+                .long("password")
+                .short("p")
+                .help("Ask for the account password before connecting"),
+        )
+        .arg(
+            Arg::with_name("tls")
+                .long("tls")
+                .help("Encrypt the connection with the server"),
+        )
+        .arg(
+            Arg::with_name("tls-ca")
+                .long("tls-ca")
+                .takes_value(true)
+                .help("PEM file with the certificates to trust, e.g. a self-signed server certificate. Implies --tls"),
+        )
+        .arg(
+            Arg::with_name("tls-fingerprint")
+                .long("tls-fingerprint")
+                .takes_value(true)
+                .help("Only trust the server certificate with this sha256 fingerprint. Implies --tls"),
+        )
         .get_matches();
 
+    let ask_password = matches.is_present("password");
+
     // The next unwraps are safe because we specified a default value and a validator
     let config = Config::from_matches(matches);
 
-    let result = match Application::new(&config) {
-        Ok(mut app) => app.run(std::io::stdout()),
+    let result = match config.credential(ask_password) {
+        Ok(credential) => match Application::new(&config, credential) {
+            Ok(mut app) => app.run(std::io::stdout()),
+            Err(e) => Err(e),
+        },
         Err(e) => Err(e),
     };
 
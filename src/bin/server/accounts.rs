@@ -0,0 +1,105 @@
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::{SaltString, rand_core::OsRng};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use subtle::ConstantTimeEq;
+use termchat_wan::message::Credential;
+use termchat_wan::util::Result;
+
+pub const INVALID_CREDENTIALS: &str = "invalid credentials";
+
+/// Entry of the credentials file.
+/// `password` is an argon2 PHC string and `token` the sha256 hex digest of the token,
+/// as printed by the `--hash-password` and `--hash-token` server options.
+#[derive(Deserialize)]
+struct Account {
+    password: Option<String>,
+    token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CredentialsFile {
+    #[serde(default)]
+    users: HashMap<String, Account>,
+}
+
+/// Result of checking the credential of a logon.
+pub enum Authentication {
+    Accepted,
+    Rejected(String),
+    /// The password must still be checked against its argon2 hash with `verify_password`,
+    /// slow on purpose so it is done out of the network thread.
+    Verify(String, String),
+}
+
+pub struct Accounts {
+    users: HashMap<String, Account>,
+    allow_anonymous: bool,
+}
+
+impl Accounts {
+    /// Every name is accepted without credentials.
+    pub fn anonymous() -> Accounts {
+        Accounts { users: HashMap::new(), allow_anonymous: true }
+    }
+
+    /// Reads the accounts from a toml file like:
+    /// ```toml
+    /// [users.alice]
+    /// password = "$argon2id$v=19$..."
+    /// [users.bot]
+    /// token = "9f86d081884c7d65..."
+    /// ```
+    /// If `allow_anonymous` is set, names not listed in the file can log on without credentials.
+    pub fn from_file(path: &Path, allow_anonymous: bool) -> Result<Accounts> {
+        let content = std::fs::read_to_string(path)?;
+        let file: CredentialsFile = toml::from_str(&content)?;
+        Ok(Accounts { users: file.users, allow_anonymous })
+    }
+
+    pub fn is_registered(&self, user: &str) -> bool {
+        self.users.contains_key(user)
+    }
+
+    /// Checks everything but the argon2 hash of a password, see `Authentication::Verify`.
+    pub fn authenticate(&self, user: &str, credential: Credential) -> Authentication {
+        let account = match self.users.get(user) {
+            Some(account) => account,
+            None if self.allow_anonymous => return Authentication::Accepted,
+            None => return Authentication::Rejected("this server only accepts registered accounts".into()),
+        };
+
+        match (credential, &account.password, &account.token) {
+            (Credential::Anonymous, _, _) => {
+                Authentication::Rejected(format!("the name '{}' requires a password or a token", user))
+            }
+            (Credential::Password(password), Some(hash), _) => Authentication::Verify(password, hash.clone()),
+            (Credential::Token(token), _, Some(hash)) if token_matches(&token, hash) => Authentication::Accepted,
+            _ => Authentication::Rejected(INVALID_CREDENTIALS.into()),
+        }
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt).map_err(|e| e.to_string())?;
+    Ok(hash.to_string())
+}
+
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Compared in constant time, the time taken tells nothing about the hash.
+fn token_matches(token: &str, hash: &str) -> bool {
+    hash_token(token).as_bytes().ct_eq(hash.to_lowercase().as_bytes()).into()
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,202 @@
+use super::bans::Ban;
+use super::Server;
+use message_io::network::Endpoint;
+use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use termchat_wan::message::NetMessage;
+
+const HELP: &str = "\
+users                        List the connected endpoints
+kick <user|ip>               Disconnect the endpoints of a user or an address
+ban <user|ip> [duration]     Ban a name or an address, forever without duration
+unban <user|ip>              Remove a ban
+bans                         List the bans
+mute <user> [duration]       Forbid a user to write in the rooms
+unmute <user>                Allow a muted user to write again
+broadcast <text>             Send a notice to every user
+Durations are like 30s, 10m, 2h or 7d";
+
+/// Parses durations like `30s`, `10m`, `2h` or `7d`.
+fn parse_duration(text: &str) -> Option<Duration> {
+    let seconds = match text.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let number = text[..text.len() - 1].parse::<u64>().ok()?;
+    Some(Duration::from_secs(number.checked_mul(seconds)?))
+}
+
+fn describe(duration: Option<Duration>) -> String {
+    let seconds = match duration {
+        Some(duration) => duration.as_secs(),
+        None => return String::from("forever"),
+    };
+    let units = [(24 * 60 * 60, 'd'), (60 * 60, 'h'), (60, 'm')];
+    match units.iter().find(|(unit, _)| seconds > 0 && seconds % unit == 0) {
+        Some((unit, suffix)) => format!("for {}{}", seconds / unit, suffix),
+        None => format!("for {}s", seconds),
+    }
+}
+
+impl Server {
+    /// Runs a command of the admin console, the result is printed in the server output.
+    pub fn handle_admin_command(&mut self, line: &str) {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return,
+        };
+        if command == "broadcast" {
+            let notice = words.collect::<Vec<_>>().join(" ");
+            if notice.is_empty() {
+                println!("[Admin] 'broadcast' needs a text, see 'help'");
+                return;
+            }
+            println!("[Notice] {}", notice);
+            self.send_all(&self.db.all_endpoints(), &NetMessage::Notice(notice));
+            return;
+        }
+
+        let target = words.next();
+        let duration = match words.next() {
+            Some(text) => match parse_duration(text) {
+                Some(duration) => Some(duration),
+                None => {
+                    println!("[Admin] invalid duration '{}', see 'help'", text);
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        match (command, target) {
+            ("help", _) => println!("{}", HELP),
+            ("users", _) => self.print_users(),
+            ("bans", _) => {
+                for ban in self.bans.list() {
+                    println!("{}", ban);
+                }
+            }
+            ("kick", Some(target)) => {
+                let endpoints = self.find_endpoints(target);
+                if endpoints.is_empty() {
+                    println!("[Admin] no user or address '{}' connected", target);
+                }
+                for endpoint in endpoints {
+                    self.kick(endpoint, "kicked by the administrator");
+                }
+            }
+            ("ban", Some(target)) => self.ban(target, duration),
+            ("unban", Some(target)) => match self.bans.unban(target) {
+                Ok(true) => println!("[Admin] '{}' unbanned", target),
+                Ok(false) => println!("[Admin] '{}' was not banned", target),
+                Err(e) => println!("[Admin] unable to store the bans: {}", e),
+            },
+            ("mute", Some(user)) => {
+                let until = match duration {
+                    Some(duration) => match Instant::now().checked_add(duration) {
+                        Some(until) => Some(until),
+                        None => {
+                            println!("[Admin] invalid duration, see 'help'");
+                            return;
+                        }
+                    },
+                    None => None,
+                };
+                self.muted.insert(user.to_string(), until);
+                println!("[Admin] '{}' muted {}", user, describe(duration));
+                if let Some(endpoint) = self.db.get_endpoint_by_user(&user.to_string()) {
+                    let notice = format!("You were muted {}", describe(duration));
+                    self.send(*endpoint, &NetMessage::Notice(notice));
+                }
+            }
+            ("unmute", Some(user)) => match self.muted.remove(user) {
+                Some(_) => {
+                    println!("[Admin] '{}' unmuted", user);
+                    if let Some(endpoint) = self.db.get_endpoint_by_user(&user.to_string()) {
+                        self.send(*endpoint, &NetMessage::Notice(String::from("You can write again")));
+                    }
+                }
+                None => println!("[Admin] '{}' was not muted", user),
+            },
+            ("kick", None) | ("ban", None) | ("unban", None) | ("mute", None) | ("unmute", None) => {
+                println!("[Admin] '{}' needs an argument, see 'help'", command);
+            }
+            _ => println!("[Admin] unknown command '{}', see 'help'", command),
+        }
+    }
+
+    /// Real address of the peer, also when it is connected through TLS.
+    pub fn peer_ip(&self, endpoint: Endpoint) -> IpAddr {
+        let addr = self.tls_acceptor.as_ref().and_then(|acceptor| acceptor.peer_addr(endpoint.addr()));
+        addr.unwrap_or_else(|| endpoint.addr()).ip()
+    }
+
+    /// Whether the user can not write in the rooms, the expired mutes are removed.
+    pub fn is_muted(&mut self, user: &str) -> bool {
+        match self.muted.get(user) {
+            Some(Some(until)) if *until <= Instant::now() => {
+                self.muted.remove(user);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Closes the connection of the endpoint, the client does not connect again by itself.
+    pub fn kick(&mut self, endpoint: Endpoint, reason: &str) {
+        println!("[Kick] {}: {}", endpoint, reason);
+        self.close(endpoint, &NetMessage::Kicked(reason.to_string()));
+    }
+
+    fn print_users(&self) {
+        let mut endpoints = self.last_beats.keys().copied().collect::<Vec<_>>();
+        endpoints.sort_by_key(|endpoint| self.db.get_user_by_endpoint(endpoint).cloned());
+        for endpoint in endpoints {
+            let ip = self.peer_ip(endpoint);
+            match self.db.get_user_by_endpoint(&endpoint) {
+                Some(user) => {
+                    let room = self.db.get_room_by_endpoint(&endpoint).cloned().unwrap_or_default();
+                    let muted = if self.muted.contains_key(user) { " (muted)" } else { "" };
+                    println!("{} {} #{}{}", user, ip, room, muted);
+                }
+                None => println!("(not logged on) {}", ip),
+            }
+        }
+    }
+
+    /// Endpoints of the user, or the endpoints connected from the address.
+    fn find_endpoints(&self, target: &str) -> Vec<Endpoint> {
+        if let Some(endpoint) = self.db.get_endpoint_by_user(&target.to_string()) {
+            return vec![*endpoint]
+        }
+        match target.parse::<IpAddr>() {
+            Ok(ip) => self.last_beats.keys().copied().filter(|endpoint| self.peer_ip(*endpoint) == ip).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn ban(&mut self, target: &str, duration: Option<Duration>) {
+        let until = duration
+            .and_then(|duration| i64::try_from(duration.as_secs()).ok())
+            .map(|seconds| chrono::Utc::now().timestamp().saturating_add(seconds));
+        let ban = Ban { until };
+        let stored = match target.parse::<IpAddr>() {
+            Ok(ip) => self.bans.ban_ip(ip, ban),
+            Err(_) => self.bans.ban_name(target, ban),
+        };
+        if let Err(e) = stored {
+            println!("[Admin] unable to store the bans: {}", e);
+        }
+        println!("[Admin] '{}' banned {}", target, ban.describe());
+        let reason = format!("banned {}", ban.describe());
+        for endpoint in self.find_endpoints(target) {
+            self.kick(endpoint, &reason);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use termchat_wan::util::Result;
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct Ban {
+    /// Unix time in seconds when the ban expires, forever without it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<i64>,
+}
+
+impl Ban {
+    fn is_active(&self, now: i64) -> bool {
+        self.until.is_none_or(|until| now < until)
+    }
+
+    pub fn describe(&self) -> String {
+        match self.until.and_then(|until| chrono::DateTime::from_timestamp(until, 0)) {
+            Some(until) => format!("until {}", until.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S")),
+            None => String::from("forever"),
+        }
+    }
+}
+
+/// Banned names and addresses, stored in a toml file every time they change:
+/// ```toml
+/// [names.alice]
+/// until = 1700000000
+///
+/// [ips."10.0.0.1"]
+/// ```
+#[derive(Serialize, Deserialize, Default)]
+pub struct Bans {
+    #[serde(default)]
+    names: HashMap<String, Ban>,
+    #[serde(default)]
+    ips: HashMap<IpAddr, Ban>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Bans {
+    /// Loads the bans of the file, that is created with the first ban.
+    pub fn load(path: &Path) -> Result<Bans> {
+        let mut bans = match std::fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Bans::default(),
+            Err(e) => return Err(e.into()),
+        };
+        bans.path = path.to_path_buf();
+        Ok(bans)
+    }
+
+    fn save(&mut self) -> Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        self.names.retain(|_, ban| ban.is_active(now));
+        self.ips.retain(|_, ban| ban.is_active(now));
+        std::fs::write(&self.path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn ban_name(&mut self, name: &str, ban: Ban) -> Result<()> {
+        self.names.insert(name.into(), ban);
+        self.save()
+    }
+
+    pub fn ban_ip(&mut self, ip: IpAddr, ban: Ban) -> Result<()> {
+        self.ips.insert(ip, ban);
+        self.save()
+    }
+
+    /// Removes the ban of a name or an address, returns if there was any.
+    pub fn unban(&mut self, target: &str) -> Result<bool> {
+        let removed = match target.parse::<IpAddr>() {
+            Ok(ip) => self.ips.remove(&ip).is_some(),
+            Err(_) => self.names.remove(target).is_some(),
+        };
+        self.save()?;
+        Ok(removed)
+    }
+
+    /// Active ban of the name or the address.
+    pub fn find(&self, name: &str, ip: Option<IpAddr>) -> Option<Ban> {
+        let now = chrono::Utc::now().timestamp();
+        let name_ban = self.names.get(name).filter(|ban| ban.is_active(now));
+        let ip_ban = ip.and_then(|ip| self.ips.get(&ip)).filter(|ban| ban.is_active(now));
+        name_ban.or(ip_ban).copied()
+    }
+
+    /// Description of the active bans.
+    pub fn list(&self) -> Vec<String> {
+        let now = chrono::Utc::now().timestamp();
+        let names = self.names.iter().map(|(name, ban)| (name.clone(), ban));
+        let ips = self.ips.iter().map(|(ip, ban)| (ip.to_string(), ban));
+        let mut bans = names
+            .chain(ips)
+            .filter(|(_, ban)| ban.is_active(now))
+            .map(|(target, ban)| format!("{} {}", target, ban.describe()))
+            .collect::<Vec<_>>();
+        bans.sort();
+        bans
+    }
+}
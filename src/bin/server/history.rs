@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use termchat_wan::message::HistoryEntry;
+use termchat_wan::util::Result;
+
+/// Messages kept in memory for each room, older ones are only in the log file.
+pub const KEPT_BY_ROOM: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    room: String,
+    entry: HistoryEntry,
+}
+
+/// Room messages of the server.
+/// With a file, every message is appended to it as a length prefixed bincode record,
+/// and the messages of previous runs are loaded from it.
+pub struct History {
+    file: Option<File>,
+    rooms: HashMap<String, VecDeque<HistoryEntry>>,
+    next_id: u64,
+}
+
+impl History {
+    pub fn in_memory() -> History {
+        History { file: None, rooms: HashMap::new(), next_id: 1 }
+    }
+
+    pub fn open(path: &Path) -> Result<History> {
+        let mut history = History::in_memory();
+        let mut file = std::fs::OpenOptions::new().read(true).append(true).create(true).open(path)?;
+
+        let mut valid_len = 0;
+        {
+            let mut reader = BufReader::new(&file);
+            loop {
+                let mut len = [0; 4];
+                if reader.read_exact(&mut len).is_err() {
+                    break
+                }
+                let mut data = vec![0; u32::from_le_bytes(len) as usize];
+                if reader.read_exact(&mut data).is_err() {
+                    break
+                }
+                let record: Record = match bincode::deserialize(&data) {
+                    Ok(record) => record,
+                    Err(_) => break,
+                };
+                valid_len += 4 + data.len() as u64;
+                history.next_id = history.next_id.max(record.entry.id + 1);
+                history.keep(record.room, record.entry);
+            }
+        }
+
+        // A record partially written by a crash is discarded.
+        if file.seek(SeekFrom::End(0))? != valid_len {
+            println!("[History] discarding a corrupted tail of {}", path.display());
+            file.set_len(valid_len)?;
+        }
+        history.file = Some(file);
+        Ok(history)
+    }
+
+    fn keep(&mut self, room: String, entry: HistoryEntry) {
+        let entries = self.rooms.entry(room).or_default();
+        entries.push_back(entry);
+        if entries.len() > KEPT_BY_ROOM {
+            entries.pop_front();
+        }
+    }
+
+    pub fn append(&mut self, room: &str, user: &str, content: &str) -> Result<HistoryEntry> {
+        let entry = HistoryEntry {
+            id: self.next_id,
+            date: chrono::Utc::now().timestamp_millis(),
+            user: user.into(),
+            content: content.into(),
+        };
+        self.next_id += 1;
+
+        if let Some(file) = &mut self.file {
+            let record = Record { room: room.into(), entry: entry.clone() };
+            let data = bincode::serialize(&record)?;
+            let mut buffer = (data.len() as u32).to_le_bytes().to_vec();
+            buffer.extend(data);
+            file.write_all(&buffer)?;
+        }
+        self.keep(room.into(), entry.clone());
+        Ok(entry)
+    }
+
+    /// Messages of the room after the `since` id, or the last `limit` ones.
+    /// An id that was never given, e.g. by a server without history file before restarting,
+    /// is ignored.
+    pub fn replay(&self, room: &str, since: Option<u64>, limit: usize) -> Vec<HistoryEntry> {
+        let since = since.filter(|since| *since < self.next_id);
+        let entries = match self.rooms.get(room) {
+            Some(entries) => entries,
+            None => return Vec::new(),
+        };
+        let newer = entries.iter().filter(|entry| since.is_none_or(|since| entry.id > since)).count();
+        entries.iter().skip(entries.len() - newer.min(limit)).cloned().collect()
+    }
+}
@@ -0,0 +1,196 @@
+use message_io::network::Endpoint;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use termchat_wan::message::{NetMessage, RateLimits};
+
+/// Seconds of traffic a client can send at once before being throttled.
+const BURST_SECONDS: f64 = 2.0;
+
+/// Tokens refilled at a constant rate, up to the burst capacity.
+/// A message bigger than the capacity is accepted with a full bucket and leaves it in debt.
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> TokenBucket {
+        let rate = f64::from(rate);
+        TokenBucket { rate, capacity: rate * BURST_SECONDS, tokens: rate * BURST_SECONDS, last: Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    fn has(&self, amount: f64) -> bool {
+        self.tokens >= amount.min(self.capacity)
+    }
+
+    /// Time until the bucket has the tokens.
+    fn wait(&self, amount: f64) -> Duration {
+        let missing = amount.min(self.capacity) - self.tokens;
+        Duration::from_secs_f64(missing.max(0.0) / self.rate)
+    }
+}
+
+/// Traffic of an endpoint.
+struct Flood {
+    ip: IpAddr,
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    // Messages delayed for going over the limits, in the order they were received.
+    backlog: VecDeque<(NetMessage, usize)>,
+    backlog_bytes: usize,
+}
+
+impl Flood {
+    /// Takes the tokens of the message if there are enough in every bucket,
+    /// else returns the time to wait for them.
+    fn take(&mut self, message: &NetMessage, size: usize) -> Result<(), Duration> {
+        // File transfers do not flood the screens, they are only limited by their size.
+        let count = match message {
+            NetMessage::SendData(_, _, _) | NetMessage::SendReceipt(_, _, _) => 0.0,
+            _ => 1.0,
+        };
+        let size = size as f64;
+        let mut buckets = [(self.messages.as_mut(), count), (self.bytes.as_mut(), size)];
+        let mut wait = Duration::ZERO;
+        for (bucket, amount) in buckets.iter_mut() {
+            if let Some(bucket) = bucket {
+                bucket.refill();
+                if !bucket.has(*amount) {
+                    wait = wait.max(bucket.wait(*amount));
+                }
+            }
+        }
+        if wait > Duration::ZERO {
+            return Err(wait)
+        }
+        for (bucket, amount) in buckets {
+            if let Some(bucket) = bucket {
+                bucket.tokens -= amount;
+            }
+        }
+        Ok(())
+    }
+
+    /// A client keeps flooding when its backlog holds more than a new burst.
+    fn is_flooding(&self) -> bool {
+        let messages = self.messages.as_ref().is_some_and(|bucket| self.backlog.len() as f64 > bucket.capacity);
+        let bytes = self.bytes.as_ref().is_some_and(|bucket| self.backlog_bytes as f64 > bucket.capacity);
+        messages || bytes
+    }
+}
+
+/// Limits configured in the server, `None` when unlimited.
+pub struct Limits {
+    pub messages: Option<u32>,
+    pub bytes: Option<u32>,
+    pub connections: Option<usize>,
+}
+
+/// What to do with a message received from an endpoint.
+pub enum Received {
+    /// Under the limits, it can be handled now.
+    Ready(NetMessage),
+    /// The client went over the limits: it is warned and the backlog is released after the duration.
+    Throttled(Duration),
+    /// Added to the backlog of a throttled client.
+    Delayed,
+    /// The client kept sending while throttled, it must be disconnected.
+    Flooding,
+}
+
+/// Applies the limits to the traffic of each endpoint.
+/// The total traffic of an address is bounded by its connections limit.
+pub struct RateLimiter {
+    limits: Limits,
+    floods: HashMap<Endpoint, Flood>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Limits) -> RateLimiter {
+        RateLimiter { limits, floods: HashMap::new() }
+    }
+
+    /// Limits announced to the clients.
+    pub fn rate_limits(&self) -> RateLimits {
+        RateLimits { messages: self.limits.messages, bytes: self.limits.bytes }
+    }
+
+    pub fn is_registered(&self, endpoint: Endpoint) -> bool {
+        self.floods.contains_key(&endpoint)
+    }
+
+    /// Starts tracking the endpoint, returns false if its address has too many connections.
+    pub fn register(&mut self, endpoint: Endpoint, ip: IpAddr) -> bool {
+        let connections = self.floods.values().filter(|flood| flood.ip == ip).count();
+        if self.limits.connections.is_some_and(|limit| connections >= limit) {
+            return false
+        }
+        let flood = Flood {
+            ip,
+            messages: self.limits.messages.map(TokenBucket::new),
+            bytes: self.limits.bytes.map(TokenBucket::new),
+            backlog: VecDeque::new(),
+            backlog_bytes: 0,
+        };
+        self.floods.insert(endpoint, flood);
+        true
+    }
+
+    pub fn unregister(&mut self, endpoint: Endpoint) {
+        self.floods.remove(&endpoint);
+    }
+
+    /// `size` is the size of the message as it was received.
+    pub fn receive(&mut self, endpoint: Endpoint, message: NetMessage, size: usize) -> Received {
+        let flood = match self.floods.get_mut(&endpoint) {
+            Some(flood) => flood,
+            None => return Received::Ready(message),
+        };
+        // The order is kept, nothing goes before the delayed messages.
+        if !flood.backlog.is_empty() {
+            flood.backlog.push_back((message, size));
+            flood.backlog_bytes += size;
+            return match flood.is_flooding() {
+                true => Received::Flooding,
+                false => Received::Delayed,
+            }
+        }
+        match flood.take(&message, size) {
+            Ok(()) => Received::Ready(message),
+            Err(wait) => {
+                flood.backlog.push_back((message, size));
+                flood.backlog_bytes += size;
+                Received::Throttled(wait)
+            }
+        }
+    }
+
+    /// Delayed messages that can be handled now,
+    /// with the time to wait for the next ones if the backlog is not empty.
+    pub fn release(&mut self, endpoint: Endpoint) -> (Vec<NetMessage>, Option<Duration>) {
+        let flood = match self.floods.get_mut(&endpoint) {
+            Some(flood) => flood,
+            None => return (Vec::new(), None),
+        };
+        let mut messages = Vec::new();
+        while let Some((message, size)) = flood.backlog.pop_front() {
+            if let Err(wait) = flood.take(&message, size) {
+                flood.backlog.push_front((message, size));
+                return (messages, Some(wait))
+            }
+            flood.backlog_bytes -= size;
+            messages.push(message);
+        }
+        (messages, None)
+    }
+}
@@ -0,0 +1,1007 @@
+mod accounts;
+mod admin;
+mod bans;
+mod history;
+mod limits;
+mod offline;
+mod relay;
+
+use accounts::{Accounts, Authentication};
+use bans::Bans;
+use history::History;
+use limits::{Limits, RateLimiter, Received};
+use offline::OfflineQueue;
+use relay::{Relay, Relayed, Transfer};
+use message_io::node::{self, NodeEvent, NodeHandler};
+use message_io::network::{Endpoint, NetEvent, Transport};
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+use std::path::Path;
+use std::time::Instant;
+use termchat_wan::message::{
+    check_room_name, Capabilities, Chunk, DeliveryStatus, NetMessage, PublicKey, Receipt, HEARTBEAT_INTERVAL, MAX_CLIENT_FRAME,
+    PROTOCOL_VERSION,
+};
+use termchat_wan::tls;
+use clap::{App, Arg};
+
+
+/// Room every user joins when logging on, and returns to when leaving another room.
+const DEFAULT_ROOM: &str = "lobby";
+const MAX_USER_NAME_LEN: usize = 32;
+const DEFAULT_REPLAY_SIZE: usize = 50;
+const DEFAULT_MISSED_BEATS: u32 = 3;
+const DEFAULT_BANS_FILE: &str = "termchat_bans.toml";
+const DEFAULT_MAX_MESSAGES: u32 = 10;
+const DEFAULT_MAX_BYTES: u32 = 1024 * 1024;
+const DEFAULT_MAX_CONNECTIONS: usize = 5;
+const DEFAULT_MAX_IN_FLIGHT: usize = 1024 * 1024;
+/// Passwords verified at the same time, the next logons with a password are rejected.
+const MAX_PENDING_LOGONS: usize = 4;
+
+/// Timers of the server.
+enum Signal {
+    /// Evicts the endpoints that stopped sending heartbeats.
+    CheckHeartbeats,
+    /// Line written in the admin console.
+    Admin(String),
+    /// Handles the delayed messages of a throttled endpoint.
+    Release(Endpoint),
+    /// The password of a pending logon was verified, with whether it is valid.
+    Verified(Endpoint, bool),
+}
+
+struct OnlineDb{
+    user_endpoint_dict: HashMap<String, Endpoint>,
+    endpoint_user_dict: HashMap<Endpoint, String>,
+    endpoint_room_dict: HashMap<Endpoint, String>,
+    room_members_dict: HashMap<String, HashSet<Endpoint>>,
+    // Last key published with each name, kept after logging off to queue messages for the user.
+    user_key_dict: HashMap<String, PublicKey>,
+}
+
+
+impl OnlineDb {
+    fn new() -> OnlineDb {
+
+        OnlineDb{
+            user_endpoint_dict:HashMap::new(),
+            endpoint_user_dict:HashMap::new(),
+            endpoint_room_dict:HashMap::new(),
+            room_members_dict:HashMap::new(),
+            user_key_dict:HashMap::new(),
+        }
+    }
+
+    fn logon(&mut self, user:String, endpoint: Endpoint){
+        self.user_endpoint_dict.insert(user.clone(), endpoint);
+        self.endpoint_user_dict.insert(endpoint, user);
+        self.join_room(endpoint, DEFAULT_ROOM);
+    }
+
+    /// Checks that the name is well formed and not used by another endpoint.
+    /// Applies both when logging on and when changing the name.
+    fn check_user_name(&self, user: &str) -> Result<(), String>{
+        if user.is_empty() {
+            Err("the name can not be empty".into())
+        }
+        else if user.chars().count() > MAX_USER_NAME_LEN {
+            Err(format!("the name can not be longer than {} characters", MAX_USER_NAME_LEN))
+        }
+        else if user.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            Err("the name can not contain spaces".into())
+        }
+        else if self.user_endpoint_dict.contains_key(user) {
+            Err(format!("the name '{}' is already in use", user))
+        }
+        else {
+            Ok(())
+        }
+    }
+
+    fn rename(&mut self, endpoint: Endpoint, user: String) -> Option<String>{
+        let old = self.endpoint_user_dict.insert(endpoint, user.clone())?;
+        self.user_endpoint_dict.remove(&old);
+        if let Some(key) = self.user_key_dict.get(&old).copied() {
+            self.user_key_dict.insert(user.clone(), key);
+        }
+        self.user_endpoint_dict.insert(user, endpoint);
+        Some(old)
+    }
+
+    fn logoff(&mut self, endpoint:Endpoint) -> Option<String>{
+        if self.endpoint_user_dict.contains_key(&endpoint) {
+            self.exit_room(endpoint);
+            if let Some(user) = self.endpoint_user_dict.get(&endpoint) {
+                self.user_endpoint_dict.remove(user);
+            }
+            self.endpoint_user_dict.remove(&endpoint)
+        }else{
+            None
+        }
+    }
+
+    fn all_endpoints(&self) -> Vec<Endpoint>{
+        self.user_endpoint_dict.values().map(|e|e.to_owned()).collect::<Vec<Endpoint>>()
+    }
+
+    fn get_endpoint_by_user(&self, user: &String) -> Option<&Endpoint>{
+        self.user_endpoint_dict.get(user)
+    }
+
+    fn get_user_by_endpoint(&self, endpoint: &Endpoint) -> Option<&String>{
+        self.endpoint_user_dict.get(endpoint)
+    }
+
+    fn set_key(&mut self, endpoint: Endpoint, key: PublicKey){
+        if let Some(user) = self.endpoint_user_dict.get(&endpoint) {
+            self.user_key_dict.insert(user.clone(), key);
+        }
+    }
+
+    /// Key of the user, online or not.
+    fn get_key_by_user(&self, user: &String) -> Option<PublicKey>{
+        self.user_key_dict.get(user).copied()
+    }
+
+    /// Moves the endpoint into the room, creating the room if it does not exist yet.
+    fn join_room(&mut self, endpoint: Endpoint, room: &str){
+        self.exit_room(endpoint);
+        self.room_members_dict.entry(room.to_string()).or_default().insert(endpoint);
+        self.endpoint_room_dict.insert(endpoint, room.to_string());
+    }
+
+    /// Removes the endpoint from its current room.
+    /// Rooms without members are removed, except the default one.
+    fn exit_room(&mut self, endpoint: Endpoint){
+        if let Some(room) = self.endpoint_room_dict.remove(&endpoint) {
+            if let Some(members) = self.room_members_dict.get_mut(&room) {
+                members.remove(&endpoint);
+                if members.is_empty() && room != DEFAULT_ROOM {
+                    self.room_members_dict.remove(&room);
+                }
+            }
+        }
+    }
+
+    fn get_room_by_endpoint(&self, endpoint: &Endpoint) -> Option<&String>{
+        self.endpoint_room_dict.get(endpoint)
+    }
+
+    fn room_endpoints(&self, room: &str) -> Vec<Endpoint>{
+        match self.room_members_dict.get(room) {
+            Some(members) => members.iter().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn user_list(&self) -> Vec<String>{
+        let mut users = self.user_endpoint_dict.keys().cloned().collect::<Vec<_>>();
+        users.sort();
+        users
+    }
+
+    fn room_list(&self) -> Vec<(String, usize)>{
+        let mut rooms = self.room_members_dict
+            .iter()
+            .map(|(room, members)| (room.clone(), members.len()))
+            .collect::<Vec<_>>();
+        rooms.sort();
+        rooms
+    }
+}
+
+fn main() {
+
+    let matches = App::new(clap::crate_name!())
+        .version(clap::crate_version!())
+        .author(clap::crate_authors!())
+        .about(clap::crate_description!())
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .short("t")
+                .takes_value(true)
+                .validator(|port| match port.parse::<u16>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("The value must be in range 0..65535".into()),
+                })
+                .help("Tcp server port "),
+        )
+        .arg(
+            Arg::with_name("credentials")
+                .long("credentials")
+                .short("c")
+                .takes_value(true)
+                .help("Toml file with the registered accounts, only they can log on"),
+        )
+        .arg(
+            Arg::with_name("anonymous")
+                .long("anonymous")
+                .short("a")
+                .requires("credentials")
+                .help("Also accept names without an account when using --credentials"),
+        )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .takes_value(true)
+                .requires("tls-key")
+                .help("PEM certificate chain, clients must connect with TLS"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .takes_value(true)
+                .requires("tls-cert")
+                .help("PEM private key of the --tls-cert certificate"),
+        )
+        .arg(
+            Arg::with_name("history")
+                .long("history")
+                .takes_value(true)
+                .help("File where the room messages are stored, they are only kept in memory without it"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .takes_value(true)
+                .validator(|size| match size.parse::<usize>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("The value must be a number".into()),
+                })
+                .help("Number of room messages sent to a user joining the room, 50 by default"),
+        )
+        .arg(
+            Arg::with_name("offline-files")
+                .long("offline-files")
+                .takes_value(true)
+                .validator(|size| match size.parse::<usize>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("The value must be a number".into()),
+                })
+                .help("Bytes of files kept for each offline user until the next logon, files are not kept by default"),
+        )
+        .arg(
+            Arg::with_name("missed-beats")
+                .long("missed-beats")
+                .takes_value(true)
+                .validator(|beats| match beats.parse::<u32>() {
+                    Ok(beats) if beats > 0 => Ok(()),
+                    _ => Err("The value must be a positive number".into()),
+                })
+                .help("Heartbeats a client can miss before being disconnected, 3 by default"),
+        )
+        .arg(
+            Arg::with_name("max-messages")
+                .long("max-messages")
+                .takes_value(true)
+                .validator(|rate| match rate.parse::<u32>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("The value must be a number".into()),
+                })
+                .help("Messages by second a client can send, file chunks apart, 10 by default, 0 for no limit"),
+        )
+        .arg(
+            Arg::with_name("max-bytes")
+                .long("max-bytes")
+                .takes_value(true)
+                .validator(|rate| match rate.parse::<u32>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("The value must be a number".into()),
+                })
+                .help("Bytes by second a client can send, 1048576 by default, 0 for no limit"),
+        )
+        .arg(
+            Arg::with_name("max-connections")
+                .long("max-connections")
+                .takes_value(true)
+                .validator(|count| match count.parse::<usize>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("The value must be a number".into()),
+                })
+                .help("Connections from the same address, 5 by default, 0 for no limit"),
+        )
+        .arg(
+            Arg::with_name("max-in-flight")
+                .long("max-in-flight")
+                .takes_value(true)
+                .validator(|size| match size.parse::<usize>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err("The value must be a number".into()),
+                })
+                .help("Bytes of files relayed to a user and not received yet, 1048576 by default, 0 for no limit"),
+        )
+        .arg(
+            Arg::with_name("bans")
+                .long("bans")
+                .takes_value(true)
+                .help("File where the bans of the admin console are stored, termchat_bans.toml by default"),
+        )
+        .arg(
+            Arg::with_name("hash-password")
+                .long("hash-password")
+                .help("Read a password from stdin and print its hash for the credentials file"),
+        )
+        .arg(
+            Arg::with_name("hash-token")
+                .long("hash-token")
+                .conflicts_with("hash-password")
+                .help("Read a token from stdin and print its hash for the credentials file"),
+        )
+        .get_matches();
+
+    if matches.is_present("hash-password") || matches.is_present("hash-token") {
+        let mut secret = String::new();
+        std::io::stdin().lock().read_line(&mut secret).expect("Unable to read stdin");
+        let secret = secret.trim_end_matches(&['\r', '\n'][..]);
+        if matches.is_present("hash-password") {
+            println!("{}", accounts::hash_password(secret).expect("Unable to hash the password"));
+        }
+        else {
+            println!("{}", accounts::hash_token(secret));
+        }
+        return;
+    }
+
+    let mut port = 5877;
+    if let Some(p) = matches.value_of("port"){
+        port = p.parse().unwrap();
+
+    }
+    let accounts = match matches.value_of("credentials") {
+        Some(path) => match Accounts::from_file(Path::new(path), matches.is_present("anonymous")) {
+            Ok(accounts) => {
+                println!("Accounts loaded from {}", path);
+                accounts
+            },
+            Err(e) => {
+                eprintln!("Unable to read the credentials file {}: {}", path, e);
+                return;
+            }
+        },
+        None => Accounts::anonymous(),
+    };
+    let history = match matches.value_of("history") {
+        Some(path) => match History::open(Path::new(path)) {
+            Ok(history) => {
+                println!("History stored in {}", path);
+                history
+            },
+            Err(e) => {
+                eprintln!("Unable to open the history file {}: {}", path, e);
+                return;
+            }
+        },
+        None => History::in_memory(),
+    };
+    let replay_size = match matches.value_of("replay") {
+        Some(size) => size.parse().unwrap(),
+        None => DEFAULT_REPLAY_SIZE,
+    };
+
+    let offline_files = match matches.value_of("offline-files") {
+        Some(size) => size.parse().unwrap(),
+        None => 0,
+    };
+
+    let missed_beats = match matches.value_of("missed-beats") {
+        Some(beats) => beats.parse().unwrap(),
+        None => DEFAULT_MISSED_BEATS,
+    };
+
+    let limits = Limits {
+        messages: Some(matches.value_of("max-messages").map_or(DEFAULT_MAX_MESSAGES, |rate| rate.parse().unwrap()))
+            .filter(|rate| *rate > 0),
+        bytes: Some(matches.value_of("max-bytes").map_or(DEFAULT_MAX_BYTES, |rate| rate.parse().unwrap()))
+            .filter(|rate| *rate > 0),
+        connections: Some(matches.value_of("max-connections").map_or(DEFAULT_MAX_CONNECTIONS, |count| count.parse().unwrap()))
+            .filter(|count| *count > 0),
+    };
+
+    let max_in_flight = Some(matches.value_of("max-in-flight").map_or(DEFAULT_MAX_IN_FLIGHT, |size| size.parse().unwrap()))
+        .filter(|size| *size > 0);
+
+    let bans_path = matches.value_of("bans").unwrap_or(DEFAULT_BANS_FILE);
+    let bans = match Bans::load(Path::new(bans_path)) {
+        Ok(bans) => bans,
+        Err(e) => {
+            eprintln!("Unable to read the bans file {}: {}", bans_path, e);
+            return;
+        }
+    };
+
+    let (handler, listener) = node::split::<Signal>();
+    let public_addr = String::from("0.0.0.0") + ":" + port.to_string().as_str();
+    // With TLS the node only listens in the loopback, the acceptor forwards the decrypted connections.
+    let tls_acceptor = match (matches.value_of("tls-cert"), matches.value_of("tls-key")) {
+        (Some(cert), Some(key)) => {
+            let (_, backend_addr) = handler.network().listen(Transport::FramedTcp, "127.0.0.1:0").unwrap();
+            let acceptor = tls::server_config(Path::new(cert), Path::new(key))
+                .and_then(|config| tls::Acceptor::spawn(public_addr.parse().unwrap(), backend_addr, config));
+            match acceptor {
+                Ok(acceptor) => {
+                    let certificate = &tls::load_certificates(Path::new(cert)).unwrap()[0];
+                    println!("TLS enabled, certificate fingerprint: {}", tls::fingerprint(certificate));
+                    Some(acceptor)
+                },
+                Err(e) => {
+                    eprintln!("Unable to enable TLS: {}", e);
+                    return;
+                }
+            }
+        },
+        _ => {
+            handler.network().listen(Transport::FramedTcp, public_addr).unwrap();
+            None
+        }
+    };
+    println!("The server is running on {} ...", port);
+    println!("Type 'help' to list the admin commands");
+
+    let admin_handler = handler.clone();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            match line {
+                Ok(line) => admin_handler.signals().send(Signal::Admin(line)),
+                Err(_) => break,
+            }
+        }
+    });
+
+    let mut server = Server {
+        handler,
+        db: OnlineDb::new(),
+        accounts,
+        history,
+        replay_size,
+        offline: OfflineQueue::new(offline_files),
+        relay: Relay::new(max_in_flight),
+        last_beats: HashMap::new(),
+        missed_beats,
+        bans,
+        muted: HashMap::new(),
+        tls_acceptor,
+        limiter: RateLimiter::new(limits),
+        capabilities: HashMap::new(),
+        pending_logons: HashMap::new(),
+    };
+    server.handler.signals().send_with_timer(Signal::CheckHeartbeats, HEARTBEAT_INTERVAL);
+    listener.for_each(move |event| match event {
+        NodeEvent::Network(net_event) => match net_event {
+            NetEvent::Connected(_, _) => unreachable!(),
+            NetEvent::Accepted(endpoint, _listener) => {
+                server.last_beats.insert(endpoint, Instant::now());
+            },
+            NetEvent::Message(endpoint, data)=>{
+                // Messages still buffered for an endpoint that was removed are ignored.
+                match server.last_beats.get_mut(&endpoint) {
+                    Some(last_beat) => *last_beat = Instant::now(),
+                    None => return,
+                }
+                match NetMessage::decode(data, MAX_CLIENT_FRAME) {
+                    Ok(msg) => server.receive(msg, data.len(), endpoint),
+                    Err(e) => {
+                        println!("[Reject] invalid message from {}: {}", endpoint, e);
+                        server.kick(endpoint, "invalid message");
+                    }
+                }
+            },
+            NetEvent::Disconnected(endpoint) => {
+                server.forget(endpoint);
+            },
+        },
+        NodeEvent::Signal(Signal::CheckHeartbeats) => {
+            server.evict_silent_endpoints();
+            server.drop_stalled_transfers();
+            server.handler.signals().send_with_timer(Signal::CheckHeartbeats, HEARTBEAT_INTERVAL);
+        },
+        NodeEvent::Signal(Signal::Admin(line)) => {
+            server.handle_admin_command(&line);
+        },
+        NodeEvent::Signal(Signal::Release(endpoint)) => {
+            server.release(endpoint);
+        },
+        NodeEvent::Signal(Signal::Verified(endpoint, valid)) => {
+            server.finish_logon(endpoint, valid);
+        },
+    });
+}
+
+struct Server {
+    handler: NodeHandler<Signal>,
+    db: OnlineDb,
+    accounts: Accounts,
+    history: History,
+    replay_size: usize,
+    offline: OfflineQueue,
+    relay: Relay,
+    // Last time each endpoint sent something, any message counts as a heartbeat.
+    last_beats: HashMap<Endpoint, Instant>,
+    missed_beats: u32,
+    bans: Bans,
+    // Users that can not write in the rooms, until the instant if any.
+    muted: HashMap<String, Option<Instant>>,
+    tls_acceptor: Option<tls::Acceptor>,
+    limiter: RateLimiter,
+    // Negotiated in the handshake of each endpoint.
+    capabilities: HashMap<Endpoint, Capabilities>,
+    // Logons whose password is being verified in another thread, with the name.
+    pending_logons: HashMap<Endpoint, String>,
+}
+
+impl Server {
+    fn send(&self, endpoint: Endpoint, message: &NetMessage){
+        let payload = message.encode(self.has_compression(endpoint));
+        self.handler.network().send(endpoint, payload.as_ref());
+    }
+
+    fn send_all(&self, endpoints: &[Endpoint], message: &NetMessage){
+        let payload = message.encode(false);
+        let mut compressed = None;
+        for e in endpoints.iter(){
+            let payload = match self.has_compression(*e) {
+                true => compressed.get_or_insert_with(|| message.encode(true)),
+                false => &payload,
+            };
+            self.handler.network().send(e.to_owned(), payload.as_ref());
+        }
+    }
+
+    fn has_compression(&self, endpoint: Endpoint) -> bool {
+        self.capabilities.get(&endpoint).is_some_and(|capabilities| capabilities.contains(Capabilities::COMPRESSION))
+    }
+
+    /// Users that can receive messages while offline, the ones whose key is known.
+    fn is_known(&self, user: &String) -> bool {
+        self.db.get_key_by_user(user).is_some()
+    }
+
+    /// Sends a last message and closes the connection.
+    fn close(&mut self, endpoint: Endpoint, message: &NetMessage){
+        self.send(endpoint, message);
+        self.handler.network().remove(endpoint.resource_id());
+        self.forget(endpoint);
+    }
+
+    /// Removes everything known about an endpoint that is not connected anymore.
+    fn forget(&mut self, endpoint: Endpoint){
+        self.last_beats.remove(&endpoint);
+        self.limiter.unregister(endpoint);
+        self.capabilities.remove(&endpoint);
+        self.pending_logons.remove(&endpoint);
+        self.logoff(endpoint);
+    }
+
+    fn logoff(&mut self, endpoint: Endpoint){
+        if let Some(u) = self.db.logoff(endpoint) {
+            println!("[LogOff] {}", u);
+            self.relay.forget(&u);
+            self.release_transfers();
+            self.send_all(&self.db.all_endpoints(), &NetMessage::Bye(u));
+        }
+    }
+
+    /// Disconnects the endpoints that missed too many heartbeats,
+    /// their connection is dead even if it was never closed.
+    fn evict_silent_endpoints(&mut self){
+        let timeout = HEARTBEAT_INTERVAL * self.missed_beats;
+        let silent = self.last_beats
+            .iter()
+            .filter(|(_, last_beat)| last_beat.elapsed() > timeout)
+            .map(|(endpoint, _)| *endpoint)
+            .collect::<Vec<_>>();
+        for endpoint in silent {
+            println!("[Evict] {} missed {} heartbeats", endpoint, self.missed_beats);
+            self.handler.network().remove(endpoint.resource_id());
+            self.forget(endpoint);
+        }
+    }
+
+    /// Drops the transfers to users that stopped receiving them, both sides are told.
+    fn drop_stalled_transfers(&mut self){
+        for transfer in self.relay.stalled() {
+            self.drop_transfer(transfer);
+        }
+    }
+
+    fn drop_transfer(&mut self, transfer: Transfer){
+        let (from, to, transfer_id) = transfer.clone();
+        println!("[DropFile] {}, {}, {:016x}", from, to, transfer_id);
+        self.relay.drop_transfer(transfer);
+        if let Some(e) = self.db.get_endpoint_by_user(&to) {
+            self.send(*e, &NetMessage::UserData(from.clone(), to.clone(), transfer_id, Chunk::Dropped));
+        }
+        if let Some(e) = self.db.get_endpoint_by_user(&from) {
+            let receipt = Receipt::Failed(format!("the server dropped it, '{}' was not receiving it fast enough", to));
+            self.send(*e, &NetMessage::UserReceipt(to, transfer_id, receipt));
+        }
+        self.release_transfers();
+    }
+
+    /// Tells the held senders to go on.
+    fn release_transfers(&mut self){
+        for ((from, to, transfer_id), offset) in self.relay.take_released() {
+            if let Some(e) = self.db.get_endpoint_by_user(&from) {
+                self.send(*e, &NetMessage::UserReceipt(to, transfer_id, Receipt::Released(offset)));
+            }
+        }
+    }
+
+    /// Checks the name of a logon and the bans, before its credential.
+    fn check_logon(&self, user: &str, endpoint: Endpoint) -> Result<(), String>{
+        self.db.check_user_name(user)?;
+        match self.bans.find(user, Some(self.peer_ip(endpoint))) {
+            Some(ban) => Err(format!("you are banned {}", ban.describe())),
+            None => Ok(()),
+        }
+    }
+
+    fn reject_logon(&self, endpoint: Endpoint, user: &str, reason: String){
+        println!("[Reject] logon {} as {}: {}", endpoint, user, reason);
+        self.send(endpoint, &NetMessage::LogonRejected(reason));
+    }
+
+    fn accept_logon(&mut self, endpoint: Endpoint, user: String, capabilities: Capabilities){
+        println!("[LogOn] {}", user);
+        self.db.logon(user.clone(), endpoint);
+        self.send(endpoint, &NetMessage::LogonAccepted(user.clone()));
+        self.send(endpoint, &NetMessage::Limits(self.limiter.rate_limits()));
+        self.send_all(&self.db.all_endpoints(), &NetMessage::Hi(user.clone()));
+        if capabilities.contains(Capabilities::ROOMS) {
+            self.send(endpoint, &NetMessage::RoomJoined(DEFAULT_ROOM.to_string()));
+        }
+        for message in self.offline.take(&user) {
+            self.send(endpoint, &message);
+        }
+    }
+
+    /// Ends a logon whose password was verified, unless the endpoint is gone.
+    fn finish_logon(&mut self, endpoint: Endpoint, valid: bool){
+        let user = match self.pending_logons.remove(&endpoint) {
+            Some(user) => user,
+            None => return,
+        };
+        let capabilities = match self.capabilities.get(&endpoint) {
+            Some(capabilities) => *capabilities,
+            None => return,
+        };
+        // The name could have been taken, or banned, meanwhile.
+        let checked = self.check_logon(&user, endpoint).and_then(|_| match valid {
+            true => Ok(()),
+            false => Err(String::from(accounts::INVALID_CREDENTIALS)),
+        });
+        match checked {
+            Ok(()) => self.accept_logon(endpoint, user, capabilities),
+            Err(reason) => self.reject_logon(endpoint, &user, reason),
+        }
+    }
+
+    /// Applies the rate limits to a message before handling it.
+    fn receive(&mut self, msg: NetMessage, size: usize, endpoint: Endpoint){
+        // Registered with the first message: through TLS the real address is only known by then.
+        if !self.limiter.is_registered(endpoint) && !self.limiter.register(endpoint, self.peer_ip(endpoint)) {
+            self.kick(endpoint, "too many connections from your address");
+            return;
+        }
+        match self.limiter.receive(endpoint, msg, size) {
+            Received::Ready(msg) => self.handle_message(msg, &endpoint),
+            Received::Throttled(wait) => {
+                println!("[Throttle] {}", endpoint);
+                let reason = String::from("your messages go over the limits of the server, they are delayed");
+                self.send(endpoint, &NetMessage::Throttled(reason));
+                self.handler.signals().send_with_timer(Signal::Release(endpoint), wait);
+            },
+            Received::Delayed => (),
+            Received::Flooding => self.kick(endpoint, "disconnected for flooding"),
+        }
+    }
+
+    /// Handles the delayed messages that are under the limits now.
+    fn release(&mut self, endpoint: Endpoint){
+        let (messages, wait) = self.limiter.release(endpoint);
+        for msg in messages {
+            self.handle_message(msg, &endpoint);
+        }
+        if let Some(wait) = wait {
+            self.handler.signals().send_with_timer(Signal::Release(endpoint), wait);
+        }
+    }
+
+    fn handle_message(&mut self, msg: NetMessage, endpoint: &Endpoint){
+
+        if let NetMessage::Handshake(version, capabilities) = msg {
+            if version != PROTOCOL_VERSION {
+                println!("[Reject] {} speaks the protocol version {}", endpoint, version);
+                let reason = format!(
+                    "the server speaks the protocol version {} and the client the version {}, both must be updated to the same version",
+                    PROTOCOL_VERSION, version
+                );
+                self.close(*endpoint, &NetMessage::Incompatible(reason));
+                return;
+            }
+            let capabilities = capabilities.intersection(Capabilities::ALL);
+            self.capabilities.insert(*endpoint, capabilities);
+            self.send(*endpoint, &NetMessage::Handshake(PROTOCOL_VERSION, capabilities));
+            return;
+        }
+
+        // Nothing else is understood before knowing the version of the client.
+        let capabilities = match self.capabilities.get(endpoint) {
+            Some(capabilities) => *capabilities,
+            None => {
+                println!("[Reject] message from {} before the handshake", endpoint);
+                self.kick(*endpoint, "the handshake is missing");
+                return;
+            }
+        };
+
+        // The heartbeats are answered even before logging on, so the client knows the server is alive.
+        if let NetMessage::BBB(timestamp) = msg {
+            self.send(*endpoint, &NetMessage::BBB(timestamp));
+            return;
+        }
+
+        if let NetMessage::Logon(user, credential) = msg {
+            if let Some(current) = self.db.get_user_by_endpoint(endpoint) {
+                println!("[Reject] {} is already logged on as {}", endpoint, current);
+                return;
+            }
+            if self.pending_logons.contains_key(endpoint) {
+                println!("[Reject] {} is already logging on", endpoint);
+                return;
+            }
+            let checked = self.check_logon(&user, *endpoint).map(|_| self.accounts.authenticate(&user, credential));
+            match checked {
+                Ok(Authentication::Accepted) => self.accept_logon(*endpoint, user, capabilities),
+                Ok(Authentication::Rejected(reason)) | Err(reason) => self.reject_logon(*endpoint, &user, reason),
+                Ok(Authentication::Verify(_, _)) if self.pending_logons.len() >= MAX_PENDING_LOGONS => {
+                    self.reject_logon(*endpoint, &user, String::from("the server is busy, try again later"));
+                },
+                Ok(Authentication::Verify(password, hash)) => {
+                    // Argon2 is slow on purpose, the other endpoints are served meanwhile.
+                    self.pending_logons.insert(*endpoint, user);
+                    let handler = self.handler.clone();
+                    let endpoint = *endpoint;
+                    std::thread::spawn(move || {
+                        let valid = accounts::verify_password(&password, &hash);
+                        handler.signals().send(Signal::Verified(endpoint, valid));
+                    });
+                },
+            }
+            return;
+        }
+
+        // Any other message must come from a logged on endpoint,
+        // the sender is always the name bound to that endpoint.
+        let user = match self.db.get_user_by_endpoint(endpoint) {
+            Some(user) => user.clone(),
+            None => {
+                println!("[Reject] message from {} before logging on", endpoint);
+                return;
+            }
+        };
+
+        match msg {
+            NetMessage::BBB(_)=>(),
+            NetMessage::Bye(_)=>{
+                self.logoff(*endpoint);
+            },
+            NetMessage::Nick(new_user)=>{
+                let checked = self.db.check_user_name(&new_user).and_then(|_| {
+                    if self.accounts.is_registered(&new_user) {
+                        Err(format!("the name '{}' belongs to a registered account", new_user))
+                    }
+                    else if self.bans.find(&new_user, None).is_some() {
+                        Err(format!("the name '{}' is banned", new_user))
+                    }
+                    else {
+                        Ok(())
+                    }
+                });
+                if let Err(reason) = checked {
+                    println!("[Reject] nick {}: {}", user, reason);
+                    self.send(*endpoint, &NetMessage::NickRejected(reason));
+                    return;
+                }
+                println!("[Nick] {}, {}", user, new_user);
+                self.db.rename(*endpoint, new_user.clone());
+                if let Some(until) = self.muted.remove(&user) {
+                    self.muted.insert(new_user.clone(), until);
+                }
+                // The transfers under the old name can not go on.
+                self.relay.forget(&user);
+                self.release_transfers();
+                self.send_all(&self.db.all_endpoints(), &NetMessage::Renamed(user, new_user));
+            },
+            NetMessage::SendMessage(message_id, content)=>{
+                if let Some(room) = self.db.get_room_by_endpoint(endpoint).cloned() {
+                    if self.is_muted(&user) {
+                        println!("[Muted] #{} {}, {}", room, user, content);
+                        let status = DeliveryStatus::Rejected(String::from("you are muted"));
+                        self.send(*endpoint, &NetMessage::Delivery(room, Some(message_id), status));
+                        return;
+                    }
+                    println!("[Message] #{} {}, {}", room, user, content);
+                    let id = match self.history.append(&room, &user, &content) {
+                        Ok(entry) => entry.id,
+                        Err(e) => {
+                            println!("[History] unable to store the message: {}", e);
+                            return;
+                        }
+                    };
+                    self.send(*endpoint, &NetMessage::Ack(message_id));
+                    self.send_all(&self.db.room_endpoints(&room), &NetMessage::UserMessage(id, user, content));
+                }
+            },
+            NetMessage::RequestHistory(_) if !capabilities.contains(Capabilities::HISTORY)=>{
+                println!("[Reject] history request of {} without the capability", user);
+            },
+            NetMessage::JoinRoom(_) | NetMessage::LeaveRoom | NetMessage::ListRooms
+                if !capabilities.contains(Capabilities::ROOMS)=>{
+                println!("[Reject] room request of {} without the capability", user);
+            },
+            NetMessage::RequestHistory(since)=>{
+                if let Some(room) = self.db.get_room_by_endpoint(endpoint) {
+                    // Without a last seen message only the last ones are sent.
+                    let limit = match since {
+                        Some(_) => history::KEPT_BY_ROOM,
+                        None => self.replay_size,
+                    };
+                    let entries = self.history.replay(room, since, limit);
+                    self.send(*endpoint, &NetMessage::History(room.clone(), entries));
+                }
+            },
+            NetMessage::PublishKey(key)=>{
+                self.db.set_key(*endpoint, key);
+            },
+            NetMessage::RequestKey(key_user)=>{
+                let key = self.db.get_key_by_user(&key_user);
+                self.send(*endpoint, &NetMessage::UserKey(key_user, key));
+            },
+            NetMessage::SendWhisper(id, to, sealed)=>{
+                println!("[WhisperMessage] {}, {}", user, to);
+                if let Some(e) = self.db.get_endpoint_by_user(&to) {
+                    self.send(*e, &NetMessage::WhisperMessage(id, user, to, sealed));
+                    self.send(*endpoint, &NetMessage::Ack(id));
+                }
+                else if !self.is_known(&to) {
+                    self.send(*endpoint, &NetMessage::Delivery(to, Some(id), DeliveryStatus::UnknownUser));
+                }
+                else {
+                    let status = self.offline.push_whisper(id, user, to.clone(), sealed);
+                    self.send(*endpoint, &NetMessage::Delivery(to, Some(id), status));
+                }
+            },
+            NetMessage::SendPing(to, timestamp)=>{
+                match self.db.get_endpoint_by_user(&to) {
+                    Some(e) => self.send(*e, &NetMessage::UserPing(user, timestamp)),
+                    None => {
+                        let status = DeliveryStatus::Rejected(String::from("the user is not online"));
+                        self.send(*endpoint, &NetMessage::Delivery(to, None, status));
+                    }
+                }
+            },
+            NetMessage::SendPong(to, timestamp)=>{
+                if let Some(e) = self.db.get_endpoint_by_user(&to) {
+                    self.send(*e, &NetMessage::UserPong(user, timestamp));
+                }
+            },
+            NetMessage::ConfirmDelivery(from, id)=>{
+                if let Some(e) = self.db.get_endpoint_by_user(&from) {
+                    self.send(*e, &NetMessage::Delivered(user, id));
+                }
+            },
+            NetMessage::SendData(to, transfer_id, chunk)=>{
+                println!("[SendFile] {}, {}, {:016x}", user, to, transfer_id);
+                if let Chunk::Dropped = chunk {
+                    println!("[Reject] chunk of the server sent by {}", user);
+                    return;
+                }
+                if self.offline.discards(&user, &to, transfer_id, &chunk) {
+                    return;
+                }
+                if let Some(e) = self.db.get_endpoint_by_user(&to).copied() {
+                    match self.relay.relay(&user, &to, transfer_id, &chunk) {
+                        Relayed::Forward => self.send(e, &NetMessage::UserData(user, to, transfer_id, chunk)),
+                        Relayed::Held(offset) => {
+                            println!("[HoldFile] {}, {}, {:016x}", user, to, transfer_id);
+                            self.send(*endpoint, &NetMessage::UserReceipt(to, transfer_id, Receipt::Held(offset)));
+                        },
+                        Relayed::Discarded => (),
+                        Relayed::Dropped => self.drop_transfer((user, to, transfer_id)),
+                    }
+                }
+                else if !self.is_known(&to) {
+                    // Answered once by transfer, the sender stops at the offer.
+                    if let Chunk::Offer(_) = chunk {
+                        let receipt = Receipt::Failed(String::from("the user is unknown"));
+                        self.send(*endpoint, &NetMessage::UserReceipt(to, transfer_id, receipt));
+                    }
+                }
+                else {
+                    // The recipient can not acknowledge the chunks, the server does it when it keeps them.
+                    let mut receipt = match chunk {
+                        // The sender waits for the recipient to answer the offer.
+                        Chunk::Offer(_) => Some(Receipt::Queued(0)),
+                        Chunk::Data(offset, _) => Some(Receipt::Queued(offset)),
+                        _ => None,
+                    };
+                    match self.offline.push_data(user, to.clone(), transfer_id, chunk) {
+                        Some(DeliveryStatus::Rejected(reason)) => receipt = Some(Receipt::Failed(reason)),
+                        Some(status) => self.send(*endpoint, &NetMessage::Delivery(to.clone(), None, status)),
+                        None => (),
+                    }
+                    if let Some(receipt) = receipt {
+                        self.send(*endpoint, &NetMessage::UserReceipt(to, transfer_id, receipt));
+                    }
+                }
+            },
+            NetMessage::SendReceipt(to, transfer_id, receipt)=>{
+                if let Receipt::Queued(_) | Receipt::Held(_) | Receipt::Released(_) = receipt {
+                    println!("[Reject] receipt of the server sent by {}", user);
+                    return;
+                }
+                self.relay.acknowledge(&to, &user, transfer_id, &receipt);
+                // Lost if the sender is not online, it sends the chunks again until it gets one.
+                if let Some(e) = self.db.get_endpoint_by_user(&to) {
+                    self.send(*e, &NetMessage::UserReceipt(user, transfer_id, receipt));
+                }
+                self.release_transfers();
+            },
+            NetMessage::JoinRoom(room)=>{
+                // Every room keeps its own history, only well formed names are created.
+                if let Err(e) = check_room_name(&room) {
+                    println!("[Reject] room of {}: {}", user, e);
+                    self.send(*endpoint, &NetMessage::RoomRejected(room, e.to_string()));
+                    return;
+                }
+                println!("[JoinRoom] {}, {}", user, room);
+                self.db.join_room(*endpoint, &room);
+                self.send(*endpoint, &NetMessage::RoomJoined(room));
+            },
+            NetMessage::LeaveRoom=>{
+                println!("[LeaveRoom] {}", user);
+                self.db.join_room(*endpoint, DEFAULT_ROOM);
+                self.send(*endpoint, &NetMessage::RoomJoined(DEFAULT_ROOM.to_string()));
+            },
+            NetMessage::RequestRoster=>{
+                self.send(*endpoint, &NetMessage::Roster(self.db.user_list()));
+            },
+            NetMessage::ListRooms=>{
+                self.send(*endpoint, &NetMessage::RoomList(self.db.room_list()));
+            },
+            NetMessage::Logon(_, _)=>{
+                println!("[Reject] {} is already logged on", user);
+            },
+            NetMessage::Handshake(_, _)=>(),
+            NetMessage::Hi(_)
+            | NetMessage::LogonAccepted(_)
+            | NetMessage::LogonRejected(_)
+            | NetMessage::NickRejected(_)
+            | NetMessage::Renamed(_, _)
+            | NetMessage::UserMessage(_, _, _)
+            | NetMessage::UserKey(_, _)
+            | NetMessage::WhisperMessage(_, _, _, _)
+            | NetMessage::UserData(_, _, _, _)
+            | NetMessage::UserReceipt(_, _, _)
+            | NetMessage::UserPing(_, _)
+            | NetMessage::UserPong(_, _)
+            | NetMessage::RoomJoined(_)
+            | NetMessage::RoomRejected(_, _)
+            | NetMessage::RoomList(_)
+            | NetMessage::History(_, _)
+            | NetMessage::Roster(_)
+            | NetMessage::Delivery(_, _, _)
+            | NetMessage::Ack(_)
+            | NetMessage::Delivered(_, _)
+            | NetMessage::Notice(_)
+            | NetMessage::Kicked(_)
+            | NetMessage::Limits(_)
+            | NetMessage::Throttled(_)
+            | NetMessage::Incompatible(_)=>{
+                println!("[Reject] server message sent by {}", user);
+            },
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::{HashMap, HashSet};
+use termchat_wan::message::{Chunk, DeliveryStatus, MessageId, NetMessage, Sealed, TransferId};
+
+/// Whispers that can be queued for each offline user, the next ones are rejected.
+const MAX_QUEUED_WHISPERS: usize = 100;
+
+/// (sender, recipient, transfer id) of a file transfer.
+type Transfer = (String, String, TransferId);
+
+/// Whispers and files sent to users that are not online, delivered when they log on.
+/// The data is end to end encrypted, so it is stored as it was received.
+pub struct OfflineQueue {
+    messages: HashMap<String, Vec<NetMessage>>,
+    whispers: HashMap<String, usize>,
+    file_bytes: HashMap<String, usize>,
+    file_limit: usize,
+    // Transfers with queued chunks, the sender is only answered for the first one.
+    transfers: HashSet<Transfer>,
+    // Transfers too big to be queued, their next chunks are ignored.
+    dropped: HashSet<Transfer>,
+}
+
+impl OfflineQueue {
+    /// `file_limit` is the file data that can be queued for each user, 0 to not queue files.
+    pub fn new(file_limit: usize) -> OfflineQueue {
+        OfflineQueue {
+            messages: HashMap::new(),
+            whispers: HashMap::new(),
+            file_bytes: HashMap::new(),
+            file_limit,
+            transfers: HashSet::new(),
+            dropped: HashSet::new(),
+        }
+    }
+
+    pub fn push_whisper(&mut self, id: MessageId, from: String, to: String, sealed: Sealed) -> DeliveryStatus {
+        let whispers = self.whispers.entry(to.clone()).or_default();
+        if *whispers >= MAX_QUEUED_WHISPERS {
+            return DeliveryStatus::Rejected(format!("too many private messages are waiting for '{}'", to))
+        }
+        *whispers += 1;
+        self.messages.entry(to.clone()).or_default().push(NetMessage::WhisperMessage(id, from, to, sealed));
+        DeliveryStatus::Queued
+    }
+
+    /// Queues a chunk of a file, the status is only returned for the first chunk of each transfer
+    /// or when the transfer can not be queued anymore.
+    pub fn push_data(&mut self, from: String, to: String, transfer_id: TransferId, chunk: Chunk) -> Option<DeliveryStatus> {
+        let transfer = (from.clone(), to.clone(), transfer_id);
+        let mut status = None;
+        match &chunk {
+            Chunk::Offer(_) => {
+                if self.transfers.insert(transfer) {
+                    status = Some(DeliveryStatus::Queued);
+                }
+            }
+            Chunk::Data(_, sealed) => {
+                let file_bytes = self.file_bytes.entry(to.clone()).or_default();
+                if *file_bytes + sealed.data.len() > self.file_limit {
+                    self.drop_transfer(&transfer);
+                    self.dropped.insert(transfer);
+                    let reason = match self.file_limit {
+                        0 => String::from("files are not kept for offline users"),
+                        limit => format!("only {} bytes of files can be kept for '{}'", limit, to),
+                    };
+                    return Some(DeliveryStatus::Rejected(reason))
+                }
+                *file_bytes += sealed.data.len();
+                if self.transfers.insert(transfer) {
+                    status = Some(DeliveryStatus::Queued);
+                }
+            }
+            Chunk::Error | Chunk::End(_) | Chunk::Cancel | Chunk::Dropped => {
+                self.transfers.remove(&transfer);
+            }
+        }
+        self.messages.entry(to.clone()).or_default().push(NetMessage::UserData(from, to, transfer_id, chunk));
+        status
+    }
+
+    /// Whether the chunk belongs to a transfer that could not be queued,
+    /// it must be ignored even if the recipient is online now.
+    pub fn discards(&mut self, from: &str, to: &str, transfer_id: TransferId, chunk: &Chunk) -> bool {
+        let transfer = (from.to_string(), to.to_string(), transfer_id);
+        match chunk {
+            // The transfer starts.
+            Chunk::Offer(_) => {
+                self.dropped.remove(&transfer);
+                false
+            }
+            Chunk::Data(_, _) => self.dropped.contains(&transfer),
+            Chunk::Error | Chunk::End(_) | Chunk::Cancel | Chunk::Dropped => self.dropped.remove(&transfer),
+        }
+    }
+
+    fn drop_transfer(&mut self, transfer: &Transfer) {
+        self.transfers.remove(transfer);
+        let (from, to, transfer_id) = transfer;
+        let mut freed = 0;
+        if let Some(messages) = self.messages.get_mut(to) {
+            messages.retain(|message| match message {
+                NetMessage::UserData(sender, _, id, chunk) if sender == from && id == transfer_id => {
+                    if let Chunk::Data(_, sealed) = chunk {
+                        freed += sealed.data.len();
+                    }
+                    false
+                }
+                _ => true,
+            });
+        }
+        if let Some(file_bytes) = self.file_bytes.get_mut(to) {
+            *file_bytes -= freed;
+        }
+    }
+
+    /// Messages waiting for the user, in the order they were sent.
+    /// The chunks of transfers still in progress are sent directly from now on.
+    pub fn take(&mut self, user: &str) -> Vec<NetMessage> {
+        self.whispers.remove(user);
+        self.file_bytes.remove(user);
+        self.transfers.retain(|(_, to, _)| to != user);
+        self.messages.remove(user).unwrap_or_default()
+    }
+}
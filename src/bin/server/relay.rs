@@ -0,0 +1,367 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use termchat_wan::message::{Chunk, Receipt, TransferId};
+
+/// Time a recipient can keep data in flight without receiving any of it, then its transfers are dropped.
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// (sender, recipient, transfer id) of a file transfer.
+pub type Transfer = (String, String, TransferId);
+
+/// What to do with a chunk sent to an online user.
+pub enum Relayed {
+    /// Under the limits, it is sent to the recipient.
+    Forward,
+    /// Too much data is in flight, the sender must be held from the offset.
+    Held(u64),
+    /// The sender is held or the transfer was dropped, the chunk is ignored.
+    Discarded,
+    /// The sender kept sending while held, the transfer must be dropped.
+    Dropped,
+}
+
+/// Chunks of a transfer relayed to the recipient.
+struct Flow {
+    // Encrypted size of the chunks not acknowledged yet, by offset.
+    in_flight: VecDeque<(u64, usize)>,
+    bytes: usize,
+    // Offset of the first chunk not relayed, the sender goes on from it once released.
+    held: Option<u64>,
+    // Bytes received while held.
+    discarded: usize,
+    // Last time the recipient acknowledged data, or the first chunk in flight was relayed.
+    last_progress: Instant,
+}
+
+impl Flow {
+    fn new() -> Flow {
+        Flow { in_flight: VecDeque::new(), bytes: 0, held: None, discarded: 0, last_progress: Instant::now() }
+    }
+
+    /// The chunks from the offset are sent again, the previous ones were lost.
+    fn rewind(&mut self, offset: u64) {
+        while self.in_flight.back().is_some_and(|(chunk, _)| *chunk >= offset) {
+            self.bytes -= self.in_flight.pop_back().unwrap().1;
+        }
+    }
+
+    fn acknowledge(&mut self, offset: u64) {
+        while self.in_flight.front().is_some_and(|(chunk, _)| *chunk < offset) {
+            self.bytes -= self.in_flight.pop_front().unwrap().1;
+        }
+        self.last_progress = Instant::now();
+    }
+}
+
+/// Bounds the file data relayed to each online user and not acknowledged by it yet.
+/// Over the limit the senders are held until the recipient catches up,
+/// so a slow recipient does not make the server buffer its data.
+pub struct Relay {
+    // Bytes in flight to a recipient, a transfer can only use half of them.
+    limit: Option<usize>,
+    flows: HashMap<Transfer, Flow>,
+    // Transfers dropped by the server, their next chunks are ignored.
+    dropped: HashSet<Transfer>,
+    // Held transfers that can go on, with the offset to send again from.
+    released: Vec<(Transfer, u64)>,
+}
+
+impl Relay {
+    /// `limit` is the data in flight to each user, `None` to relay everything.
+    pub fn new(limit: Option<usize>) -> Relay {
+        Relay { limit, flows: HashMap::new(), dropped: HashSet::new(), released: Vec::new() }
+    }
+
+    fn in_flight(&self, to: &str) -> usize {
+        self.flows.iter().filter(|((_, recipient, _), _)| recipient == to).map(|(_, flow)| flow.bytes).sum()
+    }
+
+    pub fn relay(&mut self, from: &str, to: &str, transfer_id: TransferId, chunk: &Chunk) -> Relayed {
+        let transfer = (from.to_string(), to.to_string(), transfer_id);
+        let (offset, size) = match chunk {
+            // The transfer starts.
+            Chunk::Offer(_) => {
+                self.dropped.remove(&transfer);
+                self.remove(&transfer);
+                return Relayed::Forward
+            }
+            Chunk::Data(offset, sealed) => (*offset, sealed.data.len()),
+            Chunk::End(_) => {
+                return match self.dropped.contains(&transfer) {
+                    true => Relayed::Discarded,
+                    false => Relayed::Forward,
+                }
+            }
+            Chunk::Error | Chunk::Cancel | Chunk::Dropped => {
+                self.remove(&transfer);
+                // Both sides already know about the dropped transfers.
+                return match self.dropped.remove(&transfer) {
+                    true => Relayed::Discarded,
+                    false => Relayed::Forward,
+                }
+            }
+        };
+        if self.dropped.contains(&transfer) {
+            return Relayed::Discarded
+        }
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Relayed::Forward,
+        };
+        // Data in flight to the recipient from its other transfers.
+        let others = self.in_flight(to) - self.flows.get(&transfer).map_or(0, |flow| flow.bytes);
+        let flow = self.flows.entry(transfer).or_insert_with(Flow::new);
+        if flow.held.is_some() {
+            flow.discarded += size;
+            return match flow.discarded > limit {
+                true => Relayed::Dropped,
+                false => Relayed::Discarded,
+            }
+        }
+        flow.rewind(offset);
+        let over = flow.bytes + size > limit / 2 || others + flow.bytes + size > limit;
+        // Without any data in flight nothing would release the sender, the chunk goes anyway.
+        if over && (flow.bytes > 0 || others > 0) {
+            flow.held = Some(offset);
+            return Relayed::Held(offset)
+        }
+        if flow.in_flight.is_empty() {
+            flow.last_progress = Instant::now();
+        }
+        flow.in_flight.push_back((offset, size));
+        flow.bytes += size;
+        Relayed::Forward
+    }
+
+    /// Applies a receipt sent by the recipient to the sender.
+    pub fn acknowledge(&mut self, from: &str, to: &str, transfer_id: TransferId, receipt: &Receipt) {
+        let transfer = (from.to_string(), to.to_string(), transfer_id);
+        match receipt {
+            Receipt::Received(offset) => {
+                if let Some(flow) = self.flows.get_mut(&transfer) {
+                    flow.acknowledge(*offset);
+                }
+            }
+            Receipt::Completed | Receipt::Failed(_) | Receipt::Rejected | Receipt::Cancelled => {
+                self.remove(&transfer);
+            }
+            Receipt::Accepted(_) | Receipt::Queued(_) | Receipt::Held(_) | Receipt::Released(_) => (),
+        }
+        self.release(to);
+    }
+
+    /// Releases the held transfers to the recipient once half of its data in flight was received.
+    fn release(&mut self, to: &str) {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        if self.in_flight(to) > limit / 2 {
+            return
+        }
+        for (transfer, flow) in self.flows.iter_mut().filter(|((_, recipient, _), _)| recipient == to) {
+            if flow.bytes <= limit / 4 {
+                if let Some(offset) = flow.held.take() {
+                    flow.discarded = 0;
+                    flow.last_progress = Instant::now();
+                    self.released.push((transfer.clone(), offset));
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, transfer: &Transfer) {
+        if self.flows.remove(transfer).is_some() {
+            self.release(&transfer.1);
+        }
+    }
+
+    /// Forgets the transfers of a user that logged off, from or to it.
+    pub fn forget(&mut self, user: &str) {
+        let transfers = self
+            .flows
+            .keys()
+            .filter(|(from, to, _)| from == user || to == user)
+            .cloned()
+            .collect::<Vec<_>>();
+        for transfer in transfers {
+            self.remove(&transfer);
+        }
+        self.dropped.retain(|(from, to, _)| from != user && to != user);
+        self.released.retain(|((from, to, _), _)| from != user && to != user);
+    }
+
+    /// Drops a transfer, its next chunks are ignored.
+    pub fn drop_transfer(&mut self, transfer: Transfer) {
+        self.remove(&transfer);
+        self.dropped.insert(transfer);
+    }
+
+    /// Transfers whose recipient did not receive anything for too long, they must be dropped.
+    pub fn stalled(&self) -> Vec<Transfer> {
+        self.flows
+            .iter()
+            .filter(|(_, flow)| flow.bytes > 0 && flow.last_progress.elapsed() > STALL_TIMEOUT)
+            .map(|(transfer, _)| transfer.clone())
+            .collect()
+    }
+
+    /// Held transfers that can go on, with the offset to send again from.
+    pub fn take_released(&mut self) -> Vec<(Transfer, u64)> {
+        std::mem::take(&mut self.released)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use termchat_wan::message::Sealed;
+
+    const LIMIT: usize = 100;
+
+    fn transfer(from: &str, id: TransferId) -> Transfer {
+        (from.to_string(), String::from("bob"), id)
+    }
+
+    fn sealed(size: usize) -> Sealed {
+        Sealed { sender_key: [0; 32], nonce: [0; 12], data: vec![0; size] }
+    }
+
+    fn data(offset: u64, size: usize) -> Chunk {
+        Chunk::Data(offset, sealed(size))
+    }
+
+    /// Relays a chunk of data to bob.
+    fn send(relay: &mut Relay, from: &str, id: TransferId, offset: u64, size: usize) -> Relayed {
+        relay.relay(from, "bob", id, &data(offset, size))
+    }
+
+    fn acknowledge(relay: &mut Relay, from: &str, id: TransferId, offset: u64) {
+        relay.acknowledge(from, "bob", id, &Receipt::Received(offset));
+    }
+
+    #[test]
+    fn everything_is_relayed_without_a_limit() {
+        let mut relay = Relay::new(None);
+        for offset in 0..10 {
+            assert!(matches!(send(&mut relay, "alice", 1, offset * 1000, 1000), Relayed::Forward));
+        }
+        assert!(relay.stalled().is_empty());
+    }
+
+    #[test]
+    fn a_transfer_only_uses_half_of_the_limit() {
+        let mut relay = Relay::new(Some(LIMIT));
+        assert!(matches!(send(&mut relay, "alice", 1, 0, 40), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "alice", 1, 40, 10), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "alice", 1, 50, 1), Relayed::Held(50)));
+        // The sender is held, its next chunks are not relayed.
+        assert!(matches!(send(&mut relay, "alice", 1, 51, 1), Relayed::Discarded));
+    }
+
+    #[test]
+    fn the_first_chunk_goes_even_over_the_limit() {
+        let mut relay = Relay::new(Some(LIMIT));
+        assert!(matches!(send(&mut relay, "alice", 1, 0, 2 * LIMIT), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "alice", 1, 200, 1), Relayed::Held(200)));
+    }
+
+    #[test]
+    fn transfers_to_the_same_user_share_the_limit() {
+        let mut relay = Relay::new(Some(LIMIT));
+        assert!(matches!(send(&mut relay, "alice", 1, 0, 50), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "carol", 2, 0, 45), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "dave", 3, 0, 10), Relayed::Held(0)));
+        // Other recipients have their own limit.
+        assert!(matches!(relay.relay("alice", "erin", 4, &data(0, 50)), Relayed::Forward));
+    }
+
+    #[test]
+    fn held_transfers_are_released_once_the_recipient_catches_up() {
+        let mut relay = Relay::new(Some(LIMIT));
+        assert!(matches!(send(&mut relay, "alice", 1, 0, 50), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "carol", 2, 0, 40), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "carol", 2, 40, 5), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "carol", 2, 45, 10), Relayed::Held(45)));
+
+        // Half of the limit is in flight, but the held transfer itself still has over a quarter.
+        acknowledge(&mut relay, "alice", 1, 50);
+        assert!(relay.take_released().is_empty());
+        acknowledge(&mut relay, "carol", 2, 40);
+        assert_eq!(relay.take_released(), vec![(transfer("carol", 2), 45)]);
+        assert!(relay.take_released().is_empty());
+        assert!(matches!(send(&mut relay, "carol", 2, 45, 10), Relayed::Forward));
+    }
+
+    #[test]
+    fn final_receipts_release_the_other_transfers() {
+        let mut relay = Relay::new(Some(LIMIT));
+        assert!(matches!(send(&mut relay, "alice", 1, 0, 80), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "carol", 2, 0, 30), Relayed::Held(0)));
+        relay.acknowledge("alice", "bob", 1, &Receipt::Completed);
+        assert_eq!(relay.take_released(), vec![(transfer("carol", 2), 0)]);
+    }
+
+    #[test]
+    fn chunks_sent_again_replace_the_ones_in_flight() {
+        let mut relay = Relay::new(Some(LIMIT));
+        assert!(matches!(send(&mut relay, "alice", 1, 0, 20), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "alice", 1, 20, 20), Relayed::Forward));
+        // The chunks from 20 were lost, they do not count twice.
+        assert!(matches!(send(&mut relay, "alice", 1, 20, 20), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "alice", 1, 40, 10), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "alice", 1, 50, 1), Relayed::Held(50)));
+    }
+
+    #[test]
+    fn senders_ignoring_the_hold_are_dropped() {
+        let mut relay = Relay::new(Some(LIMIT));
+        assert!(matches!(send(&mut relay, "alice", 1, 0, 50), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "alice", 1, 50, 50), Relayed::Held(50)));
+        assert!(matches!(send(&mut relay, "alice", 1, 100, 50), Relayed::Discarded));
+        assert!(matches!(send(&mut relay, "alice", 1, 150, 51), Relayed::Dropped));
+
+        relay.drop_transfer(transfer("alice", 1));
+        assert!(matches!(send(&mut relay, "alice", 1, 200, 10), Relayed::Discarded));
+        assert!(matches!(relay.relay("alice", "bob", 1, &Chunk::End(sealed(0))), Relayed::Discarded));
+        // The sender is already told, its cancel is not relayed.
+        assert!(matches!(relay.relay("alice", "bob", 1, &Chunk::Cancel), Relayed::Discarded));
+        assert!(matches!(send(&mut relay, "alice", 1, 0, 10), Relayed::Forward));
+    }
+
+    #[test]
+    fn stalled_transfers_are_reported() {
+        let mut relay = Relay::new(Some(LIMIT));
+        assert!(matches!(send(&mut relay, "alice", 1, 0, 10), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "carol", 2, 0, 10), Relayed::Forward));
+        assert!(relay.stalled().is_empty());
+
+        let stalled = Instant::now().checked_sub(STALL_TIMEOUT + Duration::from_secs(1)).unwrap();
+        for flow in relay.flows.values_mut() {
+            flow.last_progress = stalled;
+        }
+        acknowledge(&mut relay, "carol", 2, 5);
+        assert_eq!(relay.stalled(), vec![transfer("alice", 1)]);
+        // Nothing is in flight anymore, nothing is waiting for the recipient.
+        acknowledge(&mut relay, "alice", 1, 10);
+        relay.flows.get_mut(&transfer("alice", 1)).unwrap().last_progress = stalled;
+        assert!(relay.stalled().is_empty());
+    }
+
+    #[test]
+    fn forgotten_users_release_the_others() {
+        let mut relay = Relay::new(Some(LIMIT));
+        assert!(matches!(send(&mut relay, "alice", 1, 0, 80), Relayed::Forward));
+        assert!(matches!(send(&mut relay, "carol", 2, 0, 30), Relayed::Held(0)));
+        relay.drop_transfer(transfer("dave", 3));
+
+        relay.forget("alice");
+        relay.forget("dave");
+        assert_eq!(relay.take_released(), vec![(transfer("carol", 2), 0)]);
+        assert!(relay.dropped.is_empty());
+        assert!(matches!(send(&mut relay, "alice", 1, 80, 10), Relayed::Forward));
+
+        relay.forget("bob");
+        assert!(relay.flows.is_empty());
+    }
+}
@@ -1,5 +1,12 @@
 pub mod send_file;
 pub mod whisper_message;
+pub mod room;
+pub mod nick;
+pub mod trust;
+pub mod ping;
+pub mod who;
+pub mod offer;
+pub mod transfers;
 use crate::config::Config;
 use crate::action::{Action};
 use crate::util::{Result};
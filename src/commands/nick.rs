@@ -0,0 +1,53 @@
+use message_io::network::Endpoint;
+use message_io::node::NodeHandler;
+
+use crate::action::{Action, Processing};
+use crate::events::Event;
+use crate::commands::{Command};
+use crate::state::{State};
+use crate::util::{Result};
+use crate::message::{NetMessage, Credential};
+
+/// Holds the credential of the user, to log on with another name after a rejected logon.
+pub struct NickCommand {
+    credential: Credential,
+}
+
+impl NickCommand {
+    pub fn new(credential: Credential) -> NickCommand {
+        NickCommand { credential }
+    }
+}
+
+impl Command for NickCommand {
+    fn name(&self) -> &'static str {
+        "nick"
+    }
+
+    fn parse_params(&self, _user: String, params: Vec<String>) -> Result<Box<dyn Action>> {
+        let name = params.first().ok_or("No name specified")?;
+        if params.len() > 1 {
+            return Err("Names can not contain spaces".into())
+        }
+        Ok(Box::new(ChangeNick { name: name.to_string(), credential: self.credential.clone() }))
+    }
+}
+
+/// Asks the server for a new name.
+/// If the previous logon was rejected, it is retried with the new name instead.
+pub struct ChangeNick {
+    name: String,
+    credential: Credential,
+}
+
+impl Action for ChangeNick {
+    fn process(&mut self, state: &mut State, handler: NodeHandler<Event>, server: Endpoint) -> Processing {
+        let message = match state.user_name() {
+            Some(_) => NetMessage::Nick(self.name.clone()),
+            None => NetMessage::Logon(self.name.clone(), self.credential.clone()),
+        };
+        let payload = message.encode(state.compression());
+        handler.network().send(server, payload.as_ref());
+        Processing::Completed
+    }
+}
@@ -0,0 +1,64 @@
+use message_io::network::Endpoint;
+use message_io::node::NodeHandler;
+
+use crate::action::{Action, Processing};
+use crate::events::Event;
+use crate::commands::{Command};
+use crate::message::{Capabilities, NetMessage, Receipt};
+use crate::state::{OfferStatus, State};
+use crate::util::{Result, Reportable};
+
+pub struct AcceptCommand;
+
+impl Command for AcceptCommand {
+    fn name(&self) -> &'static str {
+        "accept"
+    }
+
+    fn parse_params(&self, _user: String, params: Vec<String>) -> Result<Box<dyn Action>> {
+        AnswerOffer::parse(params, true)
+    }
+}
+
+pub struct RejectCommand;
+
+impl Command for RejectCommand {
+    fn name(&self) -> &'static str {
+        "reject"
+    }
+
+    fn parse_params(&self, _user: String, params: Vec<String>) -> Result<Box<dyn Action>> {
+        AnswerOffer::parse(params, false)
+    }
+}
+
+/// Answers a file offered by another user, the sender only starts the transfer once accepted.
+pub struct AnswerOffer {
+    from: String,
+    file_name: Option<String>,
+    accept: bool,
+}
+
+impl AnswerOffer {
+    fn parse(params: Vec<String>, accept: bool) -> Result<Box<dyn Action>> {
+        let from = params.first().ok_or("No user specified")?;
+        Ok(Box::new(AnswerOffer { from: from.to_string(), file_name: params.get(1).cloned(), accept }))
+    }
+}
+
+impl Action for AnswerOffer {
+    fn process(&mut self, state: &mut State, handler: NodeHandler<Event>, server: Endpoint) -> Processing {
+        let (status, receipt) = match self.accept {
+            true => (OfferStatus::Accepted, Receipt::Accepted(Capabilities::ALL)),
+            false => (OfferStatus::Rejected, Receipt::Rejected),
+        };
+        match state.answer_offer(&self.from, self.file_name.as_deref(), status) {
+            Ok(transfer_id) => {
+                let message = NetMessage::SendReceipt(self.from.clone(), transfer_id, receipt);
+                handler.network().send(server, &message.encode(state.compression()));
+            }
+            Err(e) => e.report_err(state),
+        }
+        Processing::Completed
+    }
+}
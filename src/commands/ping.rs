@@ -0,0 +1,37 @@
+use message_io::network::Endpoint;
+use message_io::node::NodeHandler;
+
+use crate::action::{Action, Processing};
+use crate::events::Event;
+use crate::commands::{Command};
+use crate::state::{State};
+use crate::util::{self, Result};
+use crate::message::{NetMessage};
+
+pub struct PingCommand;
+
+impl Command for PingCommand {
+    fn name(&self) -> &'static str {
+        "ping"
+    }
+
+    fn parse_params(&self, _user: String, params: Vec<String>) -> Result<Box<dyn Action>> {
+        let to = params.first().ok_or("No user specified")?;
+        Ok(Box::new(Ping { to: to.to_string() }))
+    }
+}
+
+/// Measures the round trip time with another user through the server.
+/// The user answers automatically with a `UserPong` carrying the same timestamp.
+pub struct Ping {
+    to: String,
+}
+
+impl Action for Ping {
+    fn process(&mut self, state: &mut State, handler: NodeHandler<Event>, server: Endpoint) -> Processing {
+        let message = NetMessage::SendPing(self.to.clone(), util::timestamp_millis());
+        let payload = message.encode(state.compression());
+        handler.network().send(server, payload.as_ref());
+        Processing::Completed
+    }
+}
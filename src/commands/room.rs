@@ -0,0 +1,66 @@
+use message_io::network::Endpoint;
+use message_io::node::NodeHandler;
+
+use crate::action::{Action, Processing};
+use crate::events::Event;
+use crate::commands::{Command};
+use crate::state::{State};
+use crate::util::{Result, Reportable};
+use crate::message::{check_room_name, Capabilities, NetMessage};
+
+pub struct JoinRoomCommand;
+
+impl Command for JoinRoomCommand {
+    fn name(&self) -> &'static str {
+        "join"
+    }
+
+    fn parse_params(&self, _user: String, params: Vec<String>) -> Result<Box<dyn Action>> {
+        let room = params.first().ok_or("No room specified")?;
+        if params.len() > 1 {
+            return Err("Room names can not contain spaces".into())
+        }
+        check_room_name(room).map_err(|e| format!("Invalid room: {}", e))?;
+        Ok(Box::new(RoomRequest(NetMessage::JoinRoom(room.to_string()))))
+    }
+}
+
+pub struct LeaveRoomCommand;
+
+impl Command for LeaveRoomCommand {
+    fn name(&self) -> &'static str {
+        "leave"
+    }
+
+    fn parse_params(&self, _user: String, _params: Vec<String>) -> Result<Box<dyn Action>> {
+        Ok(Box::new(RoomRequest(NetMessage::LeaveRoom)))
+    }
+}
+
+pub struct ListRoomsCommand;
+
+impl Command for ListRoomsCommand {
+    fn name(&self) -> &'static str {
+        "rooms"
+    }
+
+    fn parse_params(&self, _user: String, _params: Vec<String>) -> Result<Box<dyn Action>> {
+        Ok(Box::new(RoomRequest(NetMessage::ListRooms)))
+    }
+}
+
+/// Sends a room related request to the server.
+/// The server answers with a `RoomJoined` or a `RoomList` message.
+pub struct RoomRequest(NetMessage);
+
+impl Action for RoomRequest {
+    fn process(&mut self, state: &mut State, handler: NodeHandler<Event>, server: Endpoint) -> Processing {
+        if !state.capabilities().is_some_and(|capabilities| capabilities.contains(Capabilities::ROOMS)) {
+            String::from("The server does not support rooms").report_err(state);
+            return Processing::Completed
+        }
+        let payload = self.0.encode(state.compression());
+        handler.network().send(server, payload.as_ref());
+        Processing::Completed
+    }
+}
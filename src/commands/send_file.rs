@@ -1,14 +1,17 @@
 use crate::action::{Action, Processing};
 use crate::commands::{Command};
-use crate::state::{State};
-use crate::message::{NetMessage, Chunk};
+use crate::state::{State, TransferDirection, TransferStatus};
+use crate::message::{Capabilities, NetMessage, Chunk, Receipt, TransferId};
+use crate::compression::ChunkPacker;
 use crate::util::{Result, Reportable};
+use crate::e2e::{Cipher, KeyLookup};
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use crate::transfer::{Manifest, OutgoingFiles, OFFER_TIMEOUT};
 use message_io::node::NodeHandler;
-use std::time::Duration;
-use message_io::network::Endpoint;
+use std::time::{Duration, Instant};
+use message_io::network::{Endpoint, SendStatus};
 use crate::events::Event;
-use std::path::{Path};
-use std::io::{Read};
+use std::path::{Path, PathBuf};
 
 pub struct SendFileCommand;
 
@@ -17,77 +20,310 @@ impl Command for SendFileCommand {
         "send"
     }
 
-    fn parse_params(&self, user:String, params: Vec<String>) -> Result<Box<dyn Action>> {
-        let to = params.get(0).ok_or("No transfer target")?;
-        let param = params.get(1).ok_or("No file specified")?;
-        let file_path = shellexpand::full(param)?;
-        match SendFile::new(user.as_str(), to.as_str(), &file_path) {
+    fn parse_params(&self, _user: String, params: Vec<String>) -> Result<Box<dyn Action>> {
+        let to = params.first().ok_or("No transfer target")?;
+        if params.len() < 2 {
+            return Err("No file specified".into())
+        }
+        let mut paths = Vec::new();
+        for param in &params[1..] {
+            let path = shellexpand::full(param)?;
+            if Path::new(path.as_ref()).exists() || !path.contains(&['*', '?', '['][..]) {
+                paths.push(PathBuf::from(path.as_ref()));
+                continue
+            }
+            let matches = glob::glob(&path)?.collect::<std::result::Result<Vec<_>, _>>()?;
+            if matches.is_empty() {
+                return Err(format!("No file matches '{}'", param).into())
+            }
+            paths.extend(matches);
+        }
+        match SendFile::new(to.as_str(), &paths) {
             Ok(action) => Ok(Box::new(action)),
             Err(e) => Err(e),
         }
     }
 }
 
+/// Sends files and directories as a single transfer, their data one after another.
 pub struct SendFile {
-    from: String,
     to: String,
-    file: std::fs::File,
+    // Id of the transfer on the network, its name is only sent sealed.
+    id: TransferId,
+    files: OutgoingFiles,
+    // Name of the transfer, the first file or directory given.
     file_name: String,
     file_size: u64,
-    progress_id: Option<usize>,
+    // Id in the transfers of the state, once the key of the recipient is known.
+    transfer: Option<usize>,
+    cipher: Option<Cipher>,
+    // Read a part at a time before sending the offer.
+    manifest: Option<Manifest>,
+    // Compresses the chunks if the recipient accepted the files with the capability.
+    packer: ChunkPacker,
+    // Time the offer was sent.
+    offered: Option<Instant>,
+    accepted: bool,
+    // Offset of the next chunk to send.
+    sent: u64,
+    // Every byte before this offset was received.
+    acked: u64,
+    end_sent: bool,
+    // The server relays nothing until the recipient receives the previous chunks.
+    held: bool,
+    // The recipient is offline, the server keeps the offer or the file for it.
+    queued: bool,
+    // Last time the recipient confirmed data, or the transfer started again.
+    last_progress: Instant,
 }
 
 impl SendFile {
     const CHUNK_SIZE: usize = 32768;
+    /// Chunks sent without being confirmed by the recipient.
+    const WINDOW_CHUNKS: u64 = 8;
+    const KEY_POLLING: Duration = Duration::from_millis(50);
+    const OFFER_POLLING: Duration = Duration::from_millis(100);
+    const RECEIPT_POLLING: Duration = Duration::from_millis(5);
+    const PAUSE_POLLING: Duration = Duration::from_millis(100);
+    const HOLD_POLLING: Duration = Duration::from_millis(20);
+    const RESEND_DELAY: Duration = Duration::from_millis(500);
+    /// Bytes of the files hashed for the manifest in each processing.
+    const HASH_LEN: u64 = 1 << 20;
+    const HASH_POLLING: Duration = Duration::from_millis(1);
+    /// Without receipts for this long the data is sent again from the last confirmed offset.
+    const RESUME_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new(to: &str, paths: &[PathBuf]) -> Result<SendFile> {
+        let files = OutgoingFiles::collect(paths)?;
+        Ok(SendFile {
+            to: to.to_string(),
+            id: OsRng.next_u64(),
+            file_name: files.name(),
+            file_size: files.size(),
+            files,
+            transfer: None,
+            cipher: None,
+            manifest: None,
+            packer: ChunkPacker::new(false),
+            offered: None,
+            accepted: false,
+            sent: 0,
+            acked: 0,
+            end_sent: false,
+            held: false,
+            queued: false,
+            last_progress: Instant::now(),
+        })
+    }
+
+    fn acknowledge(&mut self, state: &mut State, offset: u64) {
+        let offset = offset.min(self.file_size);
+        if offset > self.acked {
+            state.update_transfer(self.transfer.unwrap(), offset);
+            self.acked = offset;
+            // A late receipt after sending again from the last confirmed offset.
+            self.sent = self.sent.max(offset);
+            self.last_progress = Instant::now();
+        }
+    }
+
+    /// The chunks from the offset were not relayed by the server, they are sent again.
+    fn rewind(&mut self, offset: u64) {
+        if offset < self.sent {
+            self.sent = offset.max(self.acked);
+            self.end_sent = false;
+        }
+        self.last_progress = Instant::now();
+    }
 
-    pub fn new(from: &str, to: &str, file_path: &str) -> Result<SendFile> {
-        const READ_FILENAME_ERROR: &str = "Unable to read file name";
-        let from = from.to_string();
-        let to = to.to_string();
-        let file_path = Path::new(file_path);
-        let file_name = file_path
-            .file_name()
-            .ok_or(READ_FILENAME_ERROR)?
-            .to_str()
-            .ok_or(READ_FILENAME_ERROR)?
-            .to_string();
-
-        let file_size = std::fs::metadata(file_path)?.len();
-        let file = std::fs::File::open(file_path)?;
-        if file_size == 0 {
-            Err("Don't send blank file".to_string().into())
-        }else{
-            Ok(SendFile {from, to, file, file_name, file_size, progress_id: None })
+    fn finish(&mut self, state: &mut State, status: TransferStatus) -> Processing {
+        if let TransferStatus::Completed | TransferStatus::Queued = status {
+            self.acknowledge(state, self.file_size);
         }
+        state.set_transfer_status(self.transfer.unwrap(), status);
+        Processing::Completed
+    }
+
+    /// Applies the receipt of the recipient, returns `Completed` if the transfer is over.
+    fn apply_receipt(&mut self, state: &mut State, receipt: Receipt) -> Option<Processing> {
+        match receipt {
+            // Sent by the other side, an offset past the end of the files is not valid.
+            Receipt::Received(offset) | Receipt::Queued(offset) | Receipt::Held(offset) | Receipt::Released(offset)
+                if offset > self.file_size => (),
+            Receipt::Accepted(capabilities) => {
+                format!("'{}' accepted '{}'", self.to, self.file_name).report_info(state);
+                self.packer = ChunkPacker::new(capabilities.contains(Capabilities::COMPRESSION));
+                state.set_transfer_status(self.transfer.unwrap(), TransferStatus::Active);
+                self.accepted = true;
+                // Answered once online, the data goes to the recipient.
+                self.queued = false;
+                self.last_progress = Instant::now();
+            }
+            Receipt::Rejected => {
+                format!("'{}' rejected '{}'", self.to, self.file_name).report_warn(state);
+                return Some(self.finish(state, TransferStatus::Failed(String::from("rejected"))))
+            }
+            Receipt::Received(offset) => self.acknowledge(state, offset),
+            // The offer waits for the recipient to log on, it can not be answered in time.
+            Receipt::Queued(_) if !self.accepted => self.queued = true,
+            Receipt::Queued(offset) => {
+                // The chunks are kept in order, the previous ones were kept too.
+                self.acknowledge(state, offset.saturating_add(Self::CHUNK_SIZE as u64));
+                self.queued = true;
+            }
+            Receipt::Held(offset) => {
+                self.rewind(offset);
+                self.held = true;
+            }
+            Receipt::Released(offset) => {
+                self.rewind(offset);
+                self.held = false;
+            }
+            Receipt::Completed => return Some(self.finish(state, TransferStatus::Completed)),
+            Receipt::Failed(reason) => {
+                format!("Unable to send '{}' to '{}': {}", self.file_name, self.to, reason).report_err(state);
+                return Some(self.finish(state, TransferStatus::Failed(reason)))
+            }
+            Receipt::Cancelled => {
+                format!("'{}' cancelled the transfer of '{}'", self.to, self.file_name).report_warn(state);
+                return Some(self.finish(state, TransferStatus::Cancelled))
+            }
+        }
+        None
     }
 }
 
 impl Action for SendFile {
     fn process(&mut self, state: &mut State, handler: NodeHandler<Event>, server: Endpoint) -> Processing {
-        if self.progress_id.is_none() {
-            let id = state.add_progress_message(&self.file_name, self.file_size);
-            self.progress_id = Some(id);
+        if self.cipher.is_none() {
+            let compression = state.compression();
+            match state.key_ring_mut().lookup(&self.to, compression, &handler, server) {
+                KeyLookup::Ready(cipher) => self.cipher = Some(cipher),
+                KeyLookup::Waiting => return Processing::Partial(Self::KEY_POLLING),
+                KeyLookup::Failed(reason) => {
+                    format!("Unable to send '{}': {}", self.file_name, reason).report_err(state);
+                    return Processing::Completed
+                }
+            }
+        }
+
+        let transfer = match self.transfer {
+            Some(transfer) => transfer,
+            None => {
+                let transfer = state.add_transfer(TransferDirection::Sending, &self.to, self.id, &self.file_name, self.file_size);
+                self.last_progress = Instant::now();
+                *self.transfer.insert(transfer)
+            }
+        };
+
+        if let Some(receipt) = state.take_receipt(&self.to, self.id) {
+            if let Some(processing) = self.apply_receipt(state, receipt) {
+                return processing
+            }
+        }
+
+        if state.transfer(transfer).status == TransferStatus::Paused {
+            // The recipient is not expected to confirm anything meanwhile.
+            self.last_progress = Instant::now();
+            return Processing::Partial(Self::PAUSE_POLLING)
+        }
+
+        if self.manifest.is_none() {
+            // Reading all the files at once would block the interface.
+            match self.files.hash(Self::HASH_LEN) {
+                Ok(Some(manifest)) => self.manifest = Some(manifest),
+                Ok(None) => return Processing::Partial(Self::HASH_POLLING),
+                Err(error) => {
+                    format!("Unable to read '{}': {}", self.file_name, error).report_err(state);
+                    return self.finish(state, TransferStatus::Failed(error.to_string()))
+                }
+            }
         }
+        let manifest = self.manifest.as_ref().unwrap();
 
-        let mut data = [0; Self::CHUNK_SIZE];
-        let (bytes_read, chunk, processing) = match self.file.read(&mut data) {
-            Ok(0) => (0, Chunk::End, Processing::Completed),
-            Ok(bytes_read) => {
-                // We add a minor delay to introduce a rate in the sending.
-                let processing = Processing::Partial(Duration::from_micros(100));
-                (bytes_read, Chunk::Data(data[..bytes_read].to_vec()), processing)
+        if !self.accepted {
+            return match self.offered {
+                Some(offered) if offered.elapsed() > OFFER_TIMEOUT && !self.queued => {
+                    format!("'{}' did not answer the offer of '{}'", self.to, self.file_name).report_err(state);
+                    self.finish(state, TransferStatus::Failed(String::from("the offer was not answered")))
+                }
+                Some(_) => Processing::Partial(Self::OFFER_POLLING),
+                None => {
+                    let offer = Chunk::Offer(self.cipher.as_ref().unwrap().seal(&manifest.to_bytes()));
+                    let message = NetMessage::SendData(self.to.clone(), self.id, offer);
+                    match handler.network().send(server, &message.encode(state.compression())) {
+                        SendStatus::Sent => {
+                            self.offered = Some(Instant::now());
+                            Processing::Partial(Self::OFFER_POLLING)
+                        }
+                        _ => Processing::Partial(Self::RESEND_DELAY),
+                    }
+                }
             }
-            Err(error) => {
-                format!("Error sending file. error: {}", error).report_err(state);
-                (0, Chunk::Error, Processing::Completed)
+        }
+
+        // Chunks are lost if the connection of any of both users was closed while they were sent.
+        if (self.sent > self.acked || self.end_sent || self.held) && self.last_progress.elapsed() > Self::RESUME_TIMEOUT {
+            self.sent = self.acked;
+            self.end_sent = false;
+            self.held = false;
+            self.last_progress = Instant::now();
+        }
+
+        if self.held {
+            return Processing::Partial(Self::HOLD_POLLING)
+        }
+
+        let cipher = self.cipher.as_ref().unwrap();
+        let window_full = self.sent.saturating_sub(self.acked) >= Self::WINDOW_CHUNKS * Self::CHUNK_SIZE as u64;
+        let (chunk, data_len, packed_len) = if self.sent < self.file_size && !window_full {
+            let len = Self::CHUNK_SIZE.min((self.file_size - self.sent) as usize);
+            match self.files.read(self.sent, len) {
+                Ok(data) => {
+                    let packed = self.packer.pack(&data);
+                    (Chunk::Data(self.sent, cipher.seal(&packed)), data.len() as u64, packed.len() as u64)
+                }
+                Err(error) => {
+                    format!("Error sending file. error: {}", error).report_err(state);
+                    let message = NetMessage::SendData(self.to.clone(), self.id, Chunk::Error);
+                    handler.network().send(server, &message.encode(state.compression()));
+                    return self.finish(state, TransferStatus::Failed(error.to_string()))
+                }
             }
+        }
+        else if self.sent == self.file_size && !self.end_sent {
+            (Chunk::End(cipher.seal(&manifest.digest.to_bytes())), 0, 0)
+        }
+        else {
+            // Waiting for the receipts of the recipient.
+            return Processing::Partial(Self::RECEIPT_POLLING)
         };
 
-        state.progress_message_update(self.progress_id.unwrap(), bytes_read as u64);
+        let message = NetMessage::SendData(self.to.clone(), self.id, chunk);
+        let payload = message.encode(state.compression());
+        if handler.network().send(server, &payload) != SendStatus::Sent {
+            // The connection was lost, the transfer goes on once connected again.
+            return Processing::Partial(Self::RESEND_DELAY)
+        }
+        match data_len {
+            // Without the recipient nobody can check the file.
+            0 if self.queued => return self.finish(state, TransferStatus::Queued),
+            0 => self.end_sent = true,
+            _ => {
+                self.sent += data_len;
+                state.count_transfer_data(self.transfer.unwrap(), data_len, packed_len);
+            }
+        }
+        // Sending faster than the server accepts would get the client disconnected.
+        Processing::Partial(state.rate_limits().pace(payload.len()))
+    }
+
+    fn is_cancelled(&self, state: &State) -> bool {
+        self.transfer.is_some_and(|transfer| state.transfer(transfer).status == TransferStatus::Cancelled)
+    }
 
-        let message = NetMessage::UserData(self.from.to_owned(), self.to.to_string(), self.file_name.clone(), chunk);
-        let payload = bincode::serialize(&message).unwrap();
-        handler.network().send(server, payload.as_ref());
-        processing
+    fn cancel(&mut self, state: &mut State, handler: NodeHandler<Event>, server: Endpoint) {
+        let message = NetMessage::SendData(self.to.clone(), self.id, Chunk::Cancel);
+        handler.network().send(server, &message.encode(state.compression()));
     }
 }
@@ -0,0 +1,99 @@
+use message_io::network::Endpoint;
+use message_io::node::NodeHandler;
+
+use crate::action::{Action, Processing};
+use crate::events::Event;
+use crate::commands::{Command};
+use crate::state::{State, TransferStatus};
+use crate::util::{Result, Reportable};
+
+pub struct TransfersCommand;
+
+impl Command for TransfersCommand {
+    fn name(&self) -> &'static str {
+        "transfers"
+    }
+
+    fn parse_params(&self, _user: String, _params: Vec<String>) -> Result<Box<dyn Action>> {
+        Ok(Box::new(ToggleTransfers))
+    }
+}
+
+/// Shows or hides the transfers panel, like the F3 key.
+pub struct ToggleTransfers;
+
+impl Action for ToggleTransfers {
+    fn process(&mut self, state: &mut State, _: NodeHandler<Event>, _: Endpoint) -> Processing {
+        state.toggle_transfers();
+        Processing::Completed
+    }
+}
+
+pub struct CancelCommand;
+
+impl Command for CancelCommand {
+    fn name(&self) -> &'static str {
+        "cancel"
+    }
+
+    fn parse_params(&self, _user: String, params: Vec<String>) -> Result<Box<dyn Action>> {
+        ControlTransfer::parse(params, TransferStatus::Cancelled)
+    }
+}
+
+pub struct PauseCommand;
+
+impl Command for PauseCommand {
+    fn name(&self) -> &'static str {
+        "pause"
+    }
+
+    fn parse_params(&self, _user: String, params: Vec<String>) -> Result<Box<dyn Action>> {
+        ControlTransfer::parse(params, TransferStatus::Paused)
+    }
+}
+
+pub struct ResumeCommand;
+
+impl Command for ResumeCommand {
+    fn name(&self) -> &'static str {
+        "resume"
+    }
+
+    fn parse_params(&self, _user: String, params: Vec<String>) -> Result<Box<dyn Action>> {
+        ControlTransfer::parse(params, TransferStatus::Active)
+    }
+}
+
+/// Cancels, pauses or resumes a transfer given by its number in the transfers panel or its file name.
+pub struct ControlTransfer {
+    transfer: String,
+    status: TransferStatus,
+}
+
+impl ControlTransfer {
+    fn parse(params: Vec<String>, status: TransferStatus) -> Result<Box<dyn Action>> {
+        let transfer = params.first().ok_or("No transfer specified")?;
+        Ok(Box::new(ControlTransfer { transfer: transfer.to_string(), status }))
+    }
+}
+
+impl Action for ControlTransfer {
+    fn process(&mut self, state: &mut State, _: NodeHandler<Event>, _: Endpoint) -> Processing {
+        let controlled = state
+            .lookup_transfer(&self.transfer)
+            .and_then(|id| state.control_transfer(id, self.status.clone()).map(|_| id));
+        match controlled {
+            Ok(id) => {
+                let done = match self.status {
+                    TransferStatus::Cancelled => "cancelled",
+                    TransferStatus::Paused => "paused",
+                    _ => "resumed",
+                };
+                format!("Transfer of '{}' {}", state.transfer(id).file_name, done).report_info(state);
+            }
+            Err(e) => e.report_err(state),
+        }
+        Processing::Completed
+    }
+}
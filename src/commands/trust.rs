@@ -0,0 +1,38 @@
+use message_io::network::Endpoint;
+use message_io::node::NodeHandler;
+
+use crate::action::{Action, Processing};
+use crate::events::Event;
+use crate::commands::{Command};
+use crate::state::{State};
+use crate::util::{Result, Reportable};
+
+pub struct TrustCommand;
+
+impl Command for TrustCommand {
+    fn name(&self) -> &'static str {
+        "trust"
+    }
+
+    fn parse_params(&self, _user: String, params: Vec<String>) -> Result<Box<dyn Action>> {
+        let user = params.first().ok_or("No user specified")?;
+        Ok(Box::new(TrustKey { user: user.to_string() }))
+    }
+}
+
+/// Accepts the last end to end key received from a user, after it changed.
+pub struct TrustKey {
+    user: String,
+}
+
+impl Action for TrustKey {
+    fn process(&mut self, state: &mut State, _: NodeHandler<Event>, _: Endpoint) -> Processing {
+        match state.key_ring_mut().trust(&self.user) {
+            Ok(fingerprint) => {
+                format!("Trusted the key of '{}', fingerprint {}", self.user, fingerprint).report_info(state)
+            }
+            Err(e) => e.report_err(state),
+        }
+        Processing::Completed
+    }
+}
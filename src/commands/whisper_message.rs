@@ -1,12 +1,15 @@
 use message_io::network::Endpoint;
 use message_io::node::NodeHandler;
+use std::time::Duration;
 
-use crate::action::{Action, Processing};
+use crate::action::{Action, AckTimeout, Processing};
 use crate::events::Event;
 use crate::commands::{Command};
 use crate::state::{State};
-use crate::util::{Result};
-use crate::message::{NetMessage};
+use crate::util::{Result, Reportable};
+use crate::e2e::KeyLookup;
+use crate::message::{MessageId, NetMessage};
+use crate::state::MessageStatus;
 
 pub struct SendWhisperCommand;
 
@@ -15,12 +18,12 @@ impl Command for SendWhisperCommand {
         "msg"
     }
 
-    fn parse_params(&self, user:String, params: Vec<String>) -> Result<Box<dyn Action>> {
-        let to = params.get(0).ok_or("No transfer target")?;
+    fn parse_params(&self, _user: String, params: Vec<String>) -> Result<Box<dyn Action>> {
+        let to = params.first().ok_or("No transfer target")?;
         params.get(1).ok_or("Content is blank")?;
         let len = params.len();
         let content = &params[1..len].join(" ");
-        match SendWhisper::new(user.as_str(), to.as_str(), &content) {
+        match SendWhisper::new(to.as_str(), content) {
             Ok(action) => Ok(Box::new(action)),
             Err(e) => Err(e),
         }
@@ -30,27 +33,46 @@ impl Command for SendWhisperCommand {
 
 
 pub struct SendWhisper {
-    from: String,
     to: String,
     content: String,
+    id: Option<MessageId>,
 }
 
 impl SendWhisper {
 
-    pub fn new(from: &str, to: &str, content: &str) -> Result<SendWhisper> {
-        let from = from.to_string();
+    pub fn new(to: &str, content: &str) -> Result<SendWhisper> {
         let to = to.to_string();
         let content = content.to_string();
 
-        Ok(SendWhisper {from, to, content })
+        Ok(SendWhisper {to, content, id: None })
     }
 }
 
+impl SendWhisper {
+    const KEY_POLLING: Duration = Duration::from_millis(50);
+}
+
 impl Action for SendWhisper {
-    fn process(&mut self, _: &mut State, handler: NodeHandler<Event>, server: Endpoint) -> Processing {
-        let message = NetMessage::WhisperMessage(self.from.to_owned(), self.to.to_string(), self.content.clone());
-        let payload = bincode::serialize(&message).unwrap();
+    fn submitted(&mut self, state: &mut State) {
+        self.id = Some(state.track_input_message());
+    }
+
+    fn process(&mut self, state: &mut State, handler: NodeHandler<Event>, server: Endpoint) -> Processing {
+        let id = self.id.unwrap();
+        let compression = state.compression();
+        let cipher = match state.key_ring_mut().lookup(&self.to, compression, &handler, server) {
+            KeyLookup::Ready(cipher) => cipher,
+            KeyLookup::Waiting => return Processing::Partial(Self::KEY_POLLING),
+            KeyLookup::Failed(reason) => {
+                format!("Unable to send the private message: {}", reason).report_err(state);
+                state.update_message_status(id, MessageStatus::Failed);
+                return Processing::Completed
+            }
+        };
+        let message = NetMessage::SendWhisper(id, self.to.to_string(), cipher.seal(self.content.as_bytes()));
+        let payload = message.encode(compression);
         handler.network().send(server, payload.as_ref());
+        AckTimeout::schedule(&handler, id);
         Processing::Completed
     }
 }
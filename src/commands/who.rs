@@ -0,0 +1,31 @@
+use message_io::network::Endpoint;
+use message_io::node::NodeHandler;
+
+use crate::action::{Action, Processing};
+use crate::events::Event;
+use crate::commands::{Command};
+use crate::state::{State};
+use crate::util::{Result, Reportable};
+
+pub struct WhoCommand;
+
+impl Command for WhoCommand {
+    fn name(&self) -> &'static str {
+        "who"
+    }
+
+    fn parse_params(&self, _user: String, _params: Vec<String>) -> Result<Box<dyn Action>> {
+        Ok(Box::new(Who))
+    }
+}
+
+/// Prints the users online, the roster is kept up to date by the server.
+pub struct Who;
+
+impl Action for Who {
+    fn process(&mut self, state: &mut State, _: NodeHandler<Event>, _: Endpoint) -> Processing {
+        let users = state.roster().iter().cloned().collect::<Vec<_>>();
+        format!("Online ({}): {}", users.len(), users.join(", ")).report_info(state);
+        Processing::Completed
+    }
+}
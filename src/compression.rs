@@ -0,0 +1,107 @@
+use crate::util::Result;
+use std::borrow::Cow;
+
+/// Starts a compressed frame, no encoded `NetMessage` starts like it.
+const FRAME_MAGIC: [u8; 4] = [0xff; 4];
+
+/// Smaller data is not worth compressing.
+const MIN_LEN: usize = 256;
+
+/// First byte of the data of a file chunk, before it is sealed.
+const RAW_CHUNK: u8 = 0;
+const LZ4_CHUNK: u8 = 1;
+
+/// Chunks sent as they are after one that does not compress, before trying again.
+const SKIPPED_CHUNKS: u32 = 8;
+
+/// Signatures of the usual compressed formats: gzip, zip, zstd, xz, bzip2, 7z, png, jpeg and webm.
+const COMPRESSED_SIGNATURES: [&[u8]; 9] = [
+    b"\x1f\x8b",
+    b"PK\x03\x04",
+    b"\x28\xb5\x2f\xfd",
+    b"\xfd7zXZ\x00",
+    b"BZh",
+    b"7z\xbc\xaf\x27\x1c",
+    b"\x89PNG",
+    b"\xff\xd8\xff",
+    b"\x1a\x45\xdf\xa3",
+];
+
+/// LZ4 compressed data prefixed by its size, only if it saves at least an eighth of it.
+fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < MIN_LEN {
+        return None
+    }
+    let compressed = lz4_flex::block::compress_prepend_size(data);
+    match compressed.len() <= data.len() - data.len() / 8 {
+        true => Some(compressed),
+        false => None,
+    }
+}
+
+fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let (size, _) = lz4_flex::block::uncompressed_size(data)?;
+    if size > limit {
+        return Err(format!("compressed data of {} bytes, the limit is {}", size, limit).into())
+    }
+    Ok(lz4_flex::block::decompress_size_prepended(data)?)
+}
+
+/// Compresses an encoded message if it is worth it.
+pub fn compress_frame(frame: Vec<u8>) -> Vec<u8> {
+    match compress(&frame) {
+        Some(compressed) => [&FRAME_MAGIC[..], &compressed].concat(),
+        None => frame,
+    }
+}
+
+/// Encoded message of a frame, compressed or not.
+pub fn decompress_frame(frame: &[u8], limit: usize) -> Result<Cow<'_, [u8]>> {
+    match frame.strip_prefix(&FRAME_MAGIC[..]) {
+        Some(compressed) => Ok(Cow::Owned(decompress(compressed, limit)?)),
+        None => Ok(Cow::Borrowed(frame)),
+    }
+}
+
+/// Compresses the data of the file chunks of a transfer before they are sealed.
+/// Already compressed data is detected and sent as it is, without trying to compress the next chunks for a while.
+pub struct ChunkPacker {
+    enabled: bool,
+    // Chunks to send as they are before trying to compress again.
+    skip: u32,
+}
+
+impl ChunkPacker {
+    /// Disabled if the recipient can not decompress the chunks, they are still packed.
+    pub fn new(enabled: bool) -> ChunkPacker {
+        ChunkPacker { enabled, skip: 0 }
+    }
+
+    pub fn pack(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.enabled {
+            if self.skip > 0 {
+                self.skip -= 1;
+            }
+            else if let Some(compressed) = compress(data).filter(|_| !is_compressed_format(data)) {
+                return [&[LZ4_CHUNK][..], &compressed].concat()
+            }
+            else {
+                self.skip = SKIPPED_CHUNKS;
+            }
+        }
+        [&[RAW_CHUNK][..], data].concat()
+    }
+}
+
+/// Data of a file chunk packed by a `ChunkPacker`, that can not be bigger than the limit.
+pub fn unpack_chunk(packed: &[u8], limit: usize) -> Result<Vec<u8>> {
+    match packed.split_first() {
+        Some((&RAW_CHUNK, data)) => Ok(data.to_vec()),
+        Some((&LZ4_CHUNK, compressed)) => decompress(compressed, limit),
+        _ => Err("Malformed chunk of file".into()),
+    }
+}
+
+fn is_compressed_format(data: &[u8]) -> bool {
+    COMPRESSED_SIGNATURES.iter().any(|signature| data.starts_with(signature))
+}
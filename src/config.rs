@@ -1,7 +1,10 @@
 use std::net::{SocketAddrV4};
+use std::path::PathBuf;
 use clap::ArgMatches;
 use serde::{Serialize, Deserialize};
 use crate::util::Result;
+use crate::message::Credential;
+use crate::tls::Trust;
 use tui::style::Color;
 
 #[derive(Serialize, Deserialize)]
@@ -9,7 +12,58 @@ pub struct Config {
     pub server_addr: SocketAddrV4,
     pub user_name: String,
     pub terminal_bell: bool,
+    /// Users whose files are accepted without asking.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accept_files_from: Vec<String>,
+    /// Directory of the received files, `termchat` in the temporary directory by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_dir: Option<PathBuf>,
     pub theme: Theme,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub servers: Vec<ServerSettings>,
+}
+
+/// Settings used to connect to a specific server.
+///
+/// A token has precedence over a password. If none of them is set and `ask_password` is,
+/// the password is asked before connecting.
+///
+/// With `tls` the connection is encrypted. The server certificate is checked against
+/// `tls_fingerprint` if set, else against the certificates of `tls_ca`,
+/// else against the well known authorities.
+#[derive(Serialize, Deserialize)]
+pub struct ServerSettings {
+    pub server_addr: SocketAddrV4,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub ask_password: bool,
+    #[serde(default)]
+    pub tls: bool,
+    #[serde(default)]
+    pub tls_ca: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_fingerprint: Option<String>,
+    /// Name the certificate must be valid for, the server ip by default.
+    #[serde(default)]
+    pub tls_server_name: Option<String>,
+}
+
+impl ServerSettings {
+    fn new(server_addr: SocketAddrV4) -> Self {
+        ServerSettings {
+            server_addr,
+            password: None,
+            token: None,
+            ask_password: false,
+            tls: false,
+            tls_ca: None,
+            tls_fingerprint: None,
+            tls_server_name: None,
+        }
+    }
 }
 
 impl Default for Config {
@@ -19,22 +73,39 @@ impl Default for Config {
             // server_addr: "127.0.0.1:5877".parse().unwrap(),
             user_name: whoami::username(),
             terminal_bell: true,
+            accept_files_from: Vec::new(),
+            download_dir: None,
             theme: Theme::default(),
+            servers: Vec::new(),
         }
     }
 }
 
 impl Config {
-    /// Try to read config file from disk
-    /// If it does not exist, create it with default config values, and return that
-    /// If it fails for any other reason return None
-    fn from_config_file() -> Option<Self> {
+    /// Termchat directory inside the user config directory, created if it does not exist.
+    /// Other files like the end to end keys are stored next to the config file.
+    pub fn dir() -> Option<PathBuf> {
         let config_dir_path = dirs_next::config_dir()?.join("termchat");
         if let Err(e) = std::fs::create_dir_all(&config_dir_path) {
             if e.kind() != std::io::ErrorKind::AlreadyExists {
                 return None
             }
         }
+        Some(config_dir_path)
+    }
+
+    pub fn download_dir(&self) -> PathBuf {
+        match &self.download_dir {
+            Some(dir) => dir.clone(),
+            None => std::env::temp_dir().join("termchat"),
+        }
+    }
+
+    /// Try to read config file from disk
+    /// If it does not exist, create it with default config values, and return that
+    /// If it fails for any other reason return None
+    fn from_config_file() -> Option<Self> {
+        let config_dir_path = Config::dir()?;
         let config_file_path = config_dir_path.join("config");
 
         let create_config = |config_file_path| -> Result<Config> {
@@ -80,9 +151,78 @@ impl Config {
                 config.theme = Theme::light_theme();
             }
         }
+        if matches.is_present("tls") {
+            config.server_settings_mut().tls = true;
+        }
+        if let Some(tls_ca) = matches.value_of("tls-ca") {
+            let server = config.server_settings_mut();
+            server.tls = true;
+            server.tls_ca = Some(tls_ca.into());
+        }
+        if let Some(tls_fingerprint) = matches.value_of("tls-fingerprint") {
+            let server = config.server_settings_mut();
+            server.tls = true;
+            server.tls_fingerprint = Some(tls_fingerprint.into());
+        }
 
         config
     }
+
+    /// Settings of the configured server, if any.
+    pub fn server_settings(&self) -> Option<&ServerSettings> {
+        self.servers.iter().find(|server| server.server_addr == self.server_addr)
+    }
+
+    fn server_settings_mut(&mut self) -> &mut ServerSettings {
+        let index = match self.servers.iter().position(|server| server.server_addr == self.server_addr) {
+            Some(index) => index,
+            None => {
+                self.servers.push(ServerSettings::new(self.server_addr));
+                self.servers.len() - 1
+            }
+        };
+        &mut self.servers[index]
+    }
+
+    /// How to check the server certificate, `None` if the connection is not encrypted.
+    pub fn tls_trust(&self) -> Option<Trust> {
+        let server = self.server_settings().filter(|server| server.tls)?;
+        Some(match (&server.tls_fingerprint, &server.tls_ca) {
+            (Some(fingerprint), _) => Trust::Fingerprint(fingerprint.clone()),
+            (None, Some(ca)) => Trust::CaFile(ca.clone()),
+            (None, None) => Trust::WebPki,
+        })
+    }
+
+    pub fn tls_server_name(&self) -> String {
+        match self.server_settings().and_then(|server| server.tls_server_name.clone()) {
+            Some(name) => name,
+            None => self.server_addr.ip().to_string(),
+        }
+    }
+
+    /// Credential to log on the configured server.
+    /// The password is asked in the terminal if `ask_password` is set,
+    /// or if the server entry requires it and has no stored password or token.
+    pub fn credential(&self, ask_password: bool) -> Result<Credential> {
+        let server = self.server_settings();
+        let ask_password = ask_password || server.is_some_and(|server| server.ask_password);
+
+        if let Some(server) = server {
+            if let Some(token) = &server.token {
+                return Ok(Credential::Token(token.clone()))
+            }
+            if let Some(password) = &server.password {
+                return Ok(Credential::Password(password.clone()))
+            }
+        }
+
+        if ask_password {
+            let prompt = format!("Password for '{}' at {}: ", self.user_name, self.server_addr);
+            return Ok(Credential::Password(rpassword::prompt_password(prompt)?))
+        }
+        Ok(Credential::Anonymous)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -93,6 +233,9 @@ pub struct Theme {
     pub system_info_color: (Color, Color),
     pub system_warning_color: (Color, Color),
     pub system_error_color: (Color, Color),
+    /// Notices sent by the server administrator.
+    #[serde(default = "Theme::default_server_notice_color")]
+    pub server_notice_color: (Color, Color),
     pub chat_panel_color: Color,
     pub progress_bar_color: Color,
     pub command_color: Color,
@@ -114,6 +257,7 @@ impl Theme {
             system_info_color: (Color::Cyan, Color::LightCyan),
             system_warning_color: (Color::Yellow, Color::LightYellow),
             system_error_color: (Color::Red, Color::LightRed),
+            server_notice_color: Self::default_server_notice_color(),
             chat_panel_color: Color::White,
             progress_bar_color: Color::LightGreen,
             command_color: Color::LightYellow,
@@ -121,6 +265,10 @@ impl Theme {
         }
     }
 
+    fn default_server_notice_color() -> (Color, Color) {
+        (Color::Magenta, Color::LightMagenta)
+    }
+
     fn light_theme() -> Self {
         Self {
             message_colors: vec![Color::Blue, Color::Yellow, Color::Cyan, Color::Magenta],
@@ -129,6 +277,7 @@ impl Theme {
             system_info_color: (Color::Cyan, Color::LightCyan),
             system_warning_color: (Color::Yellow, Color::LightYellow),
             system_error_color: (Color::Red, Color::LightRed),
+            server_notice_color: Self::default_server_notice_color(),
             chat_panel_color: Color::Black,
             progress_bar_color: Color::LightGreen,
             command_color: Color::LightYellow,
@@ -0,0 +1,268 @@
+// End to end encryption of the whispers and the files.
+// Each user has a static x25519 keypair stored next to the config file. Both sides derive the
+// same chacha20poly1305 key from their diffie-hellman shared secret, the server only relays
+// the public keys. Keys are trusted on first use and remembered in the `known_keys` file.
+use crate::events::Event;
+use crate::message::{NetMessage, PublicKey, Sealed};
+use crate::util::Result;
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use message_io::network::Endpoint;
+use message_io::node::NodeHandler;
+use sha2::{Digest, Sha256};
+use x25519_dalek::StaticSecret;
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const IDENTITY_FILE: &str = "identity";
+const KNOWN_KEYS_FILE: &str = "known_keys";
+const KEY_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Short form of a public key to compare it out of band.
+pub fn fingerprint(key: &PublicKey) -> String {
+    let digest = format!("{:x}", Sha256::digest(key));
+    digest.as_bytes()[..32]
+        .chunks(4)
+        .map(|group| std::str::from_utf8(group).unwrap())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn to_hex(key: &PublicKey) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<PublicKey> {
+    let mut key = [0; 32];
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None
+    }
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).ok()?;
+    }
+    Some(key)
+}
+
+pub enum KeyLookup {
+    Ready(Cipher),
+    Waiting,
+    Failed(String),
+}
+
+enum PeerKey {
+    Announced(PublicKey),
+    Missing,
+}
+
+/// Encrypts and decrypts the data exchanged with one user.
+pub struct Cipher {
+    own_key: PublicKey,
+    cipher: ChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn seal(&self, data: &[u8]) -> Sealed {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        // Encrypting only fails if the data is bigger than the chacha20 limit (256 GiB).
+        let data = self.cipher.encrypt(&nonce, data).expect("Data too big to be encrypted");
+        Sealed { sender_key: self.own_key, nonce: nonce.into(), data }
+    }
+
+    pub fn open(&self, sealed: &Sealed) -> Result<Vec<u8>> {
+        let nonce = Nonce::from_slice(&sealed.nonce);
+        Ok(self.cipher.decrypt(nonce, sealed.data.as_ref()).map_err(|_| "Unable to decrypt the data")?)
+    }
+}
+
+#[derive(Default)]
+pub struct KeyRing {
+    secret: Option<StaticSecret>,
+    dir: Option<PathBuf>,
+    known: HashMap<String, PublicKey>,
+    peers: HashMap<String, PeerKey>,
+    requests: HashMap<String, Instant>,
+    warned: HashSet<PublicKey>,
+}
+
+impl KeyRing {
+    /// Loads the keypair and the known keys from the directory, creating the keypair the first time.
+    /// Without directory a temporary keypair is used and nothing is stored.
+    pub fn load(dir: Option<&Path>) -> Result<KeyRing> {
+        let mut key_ring = KeyRing { dir: dir.map(Path::to_path_buf), ..Default::default() };
+        let secret = match dir {
+            Some(dir) => {
+                let identity_path = dir.join(IDENTITY_FILE);
+                match std::fs::read(&identity_path) {
+                    Ok(bytes) => {
+                        let bytes: [u8; 32] = bytes.try_into().map_err(|_| "Corrupted identity file")?;
+                        StaticSecret::from(bytes)
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        let secret = StaticSecret::random_from_rng(OsRng);
+                        write_private(&identity_path, secret.as_bytes())?;
+                        secret
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            None => StaticSecret::random_from_rng(OsRng),
+        };
+        key_ring.secret = Some(secret);
+
+        if let Some(dir) = dir {
+            match std::fs::read_to_string(dir.join(KNOWN_KEYS_FILE)) {
+                Ok(content) => {
+                    let known: HashMap<String, String> = toml::from_str(&content)?;
+                    for (user, hex) in known {
+                        let key = from_hex(&hex).ok_or("Corrupted known keys file")?;
+                        key_ring.known.insert(user, key);
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(key_ring)
+    }
+
+    pub fn public_key(&self) -> Option<PublicKey> {
+        self.secret.as_ref().map(|secret| x25519_dalek::PublicKey::from(secret).to_bytes())
+    }
+
+    fn cipher(&self, key: &PublicKey) -> Option<Cipher> {
+        let secret = self.secret.as_ref()?;
+        let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(*key));
+        let mut hasher = Sha256::new();
+        hasher.update(b"termchat e2e");
+        hasher.update(shared.as_bytes());
+        let cipher = ChaCha20Poly1305::new(&hasher.finalize());
+        Some(Cipher { own_key: self.public_key()?, cipher })
+    }
+
+    fn save_known(&self) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            let known = self.known
+                .iter()
+                .map(|(user, key)| (user.clone(), to_hex(key)))
+                .collect::<HashMap<_, _>>();
+            std::fs::write(dir.join(KNOWN_KEYS_FILE), toml::to_string(&known)?)?;
+        }
+        Ok(())
+    }
+
+    /// Registers the key the server announced for the user, `None` if the server does not know the user.
+    /// Returns a warning for the user if the key is seen for the first time or if it changed.
+    pub fn see_key(&mut self, user: &str, key: Option<PublicKey>) -> Option<String> {
+        self.requests.remove(user);
+        let key = match key {
+            Some(key) => key,
+            None => {
+                self.peers.insert(user.into(), PeerKey::Missing);
+                return None
+            }
+        };
+        self.peers.insert(user.into(), PeerKey::Announced(key));
+
+        let warning = match self.known.get(user) {
+            Some(known) if *known == key => return None,
+            Some(_) => format!(
+                "The key of '{}' changed, fingerprint {}. Private messages with '{}' are blocked until you run '?trust {}'",
+                user, fingerprint(&key), user, user
+            ),
+            None => {
+                self.known.insert(user.into(), key);
+                if let Err(e) = self.save_known() {
+                    return Some(format!("Unable to save the key of '{}': {}", user, e))
+                }
+                format!("First private contact with '{}', key fingerprint {}", user, fingerprint(&key))
+            }
+        };
+        match self.warned.insert(key) {
+            true => Some(warning),
+            false => None,
+        }
+    }
+
+    /// Trusts the last key seen for the user, replacing the known one.
+    pub fn trust(&mut self, user: &str) -> Result<String> {
+        let key = match self.peers.get(user) {
+            Some(PeerKey::Announced(key)) => *key,
+            _ => return Err(format!("No key of '{}' was received", user).into()),
+        };
+        self.known.insert(user.into(), key);
+        self.save_known()?;
+        Ok(fingerprint(&key))
+    }
+
+    /// Forgets the cached key of a user that went offline or changed its name,
+    /// it is asked again to the server the next time.
+    pub fn forget(&mut self, user: &str) {
+        self.peers.remove(user);
+    }
+
+    /// Cipher to send data to the user.
+    /// The key is asked to the server the first time, meanwhile `KeyLookup::Waiting` is returned.
+    /// `compression` tells whether the request can be compressed, as negotiated with the server.
+    pub fn lookup(&mut self, user: &str, compression: bool, handler: &NodeHandler<Event>, server: Endpoint) -> KeyLookup {
+        match self.peers.get(user) {
+            Some(PeerKey::Announced(key)) => {
+                if self.known.get(user) != Some(key) {
+                    return KeyLookup::Failed(format!("The key of '{}' is not trusted, run '?trust {}' first", user, user))
+                }
+                match self.cipher(key) {
+                    Some(cipher) => KeyLookup::Ready(cipher),
+                    None => KeyLookup::Failed("No own key available".into()),
+                }
+            }
+            Some(PeerKey::Missing) => {
+                self.peers.remove(user);
+                KeyLookup::Failed(format!("The user '{}' is unknown", user))
+            }
+            None => match self.requests.get(user) {
+                Some(since) if since.elapsed() > KEY_REQUEST_TIMEOUT => {
+                    self.requests.remove(user);
+                    KeyLookup::Failed(format!("The server did not send the key of '{}'", user))
+                }
+                Some(_) => KeyLookup::Waiting,
+                None => {
+                    let payload = NetMessage::RequestKey(user.into()).encode(compression);
+                    handler.network().send(server, payload.as_ref());
+                    self.requests.insert(user.into(), Instant::now());
+                    KeyLookup::Waiting
+                }
+            },
+        }
+    }
+
+    /// Decrypts data sent by the user.
+    /// The key carried by the data is checked like the ones announced by the server,
+    /// so the returned warning must be shown to the user.
+    pub fn open(&mut self, user: &str, sealed: &Sealed) -> (Result<Vec<u8>>, Option<String>) {
+        let warning = self.see_key(user, Some(sealed.sender_key));
+        if self.known.get(user) != Some(&sealed.sender_key) {
+            let error = format!("Ignored private data from '{}' with an untrusted key", user);
+            return (Err(error.into()), warning)
+        }
+        match self.cipher(&sealed.sender_key) {
+            Some(cipher) => (cipher.open(sealed), warning),
+            None => (Err("No own key available".into()), warning),
+        }
+    }
+}
+
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(data)?;
+    Ok(())
+}
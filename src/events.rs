@@ -7,5 +7,9 @@ pub enum Event {
     Network(NetMessage),
     Terminal(TermEvent),
     Action(Box<dyn Action>),
+    /// Retries the connection with the server.
+    Connect,
+    /// Time to send the next heartbeat to the server.
+    Heartbeat,
     Close(Option<Error>),
 }
\ No newline at end of file
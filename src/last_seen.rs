@@ -0,0 +1,50 @@
+// Last room message seen by the user in each server.
+// It is sent to the server when joining a room so only the missed messages are replayed.
+use crate::util::Result;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const LAST_SEEN_FILE: &str = "last_seen";
+
+#[derive(Default)]
+pub struct LastSeen {
+    path: Option<PathBuf>,
+    ids: HashMap<String, u64>,
+}
+
+impl LastSeen {
+    /// Loads the ids stored in the directory. Without directory nothing is stored.
+    pub fn load(dir: Option<&Path>) -> Result<LastSeen> {
+        let path = dir.map(|dir| dir.join(LAST_SEEN_FILE));
+        let ids = match &path {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(content) => toml::from_str(&content)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+                Err(e) => return Err(e.into()),
+            },
+            None => HashMap::new(),
+        };
+        Ok(LastSeen { path, ids })
+    }
+
+    fn key(server: &str, room: &str) -> String {
+        format!("{}#{}", server, room)
+    }
+
+    pub fn get(&self, server: &str, room: &str) -> Option<u64> {
+        self.ids.get(&LastSeen::key(server, room)).copied()
+    }
+
+    pub fn update(&mut self, server: &str, room: &str, id: u64) {
+        let last = self.ids.entry(LastSeen::key(server, room)).or_insert(id);
+        *last = id.max(*last);
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(path) = &self.path {
+            std::fs::write(path, toml::to_string(&self.ids)?)?;
+        }
+        Ok(())
+    }
+}
@@ -8,3 +8,8 @@ pub mod commands;
 mod ui;
 pub mod util;
 pub mod config;
+pub mod tls;
+pub mod e2e;
+pub mod last_seen;
+pub mod transfer;
+pub mod compression;
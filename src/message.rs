@@ -1,18 +1,399 @@
+use crate::compression;
+use crate::util::Result;
+use bincode::Options;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Time between two `NetMessage::BBB` heartbeats sent by the clients.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Version of the messages, the server only accepts clients of the same version.
+pub const PROTOCOL_VERSION: u32 = 8;
+
+/// Longest string of a message in bytes, a whole input line of the user.
+pub const MAX_STRING_LEN: usize = 4096;
+
+/// Longest name of a room, in characters.
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+
+/// Biggest encrypted data of a whisper or a file chunk, in bytes.
+pub const MAX_SEALED_LEN: usize = 64 * 1024;
+
+/// Biggest frame a client sends, a file chunk with its names.
+pub const MAX_CLIENT_FRAME: u64 = 128 * 1024;
+
+/// Biggest frame the server sends, the history of a room.
+pub const MAX_SERVER_FRAME: u64 = 16 * 1024 * 1024;
+
+pub type PublicKey = [u8; 32];
+
+/// Optional features of a connection, enabled only when both sides have them.
+/// Unknown flags are ignored, so newer versions can add flags without breaking older ones.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Rooms other than the lobby: `JoinRoom`, `LeaveRoom` and `ListRooms`.
+    pub const ROOMS: Capabilities = Capabilities(1);
+    /// Room messages replayed with `RequestHistory`.
+    pub const HISTORY: Capabilities = Capabilities(1 << 1);
+    /// LZ4 compressed frames, and file chunks when the recipient accepts the file with it.
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 2);
+    /// Every capability known by this version.
+    pub const ALL: Capabilities = Capabilities(Self::ROOMS.0 | Self::HISTORY.0 | Self::COMPRESSION.0);
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// Chosen by the sender client, unique among the messages it sent.
+pub type MessageId = u64;
+
+/// Random id of a file transfer chosen by the sender, the names of the files only travel sealed.
+pub type TransferId = u64;
+
+/// Data encrypted end to end, only the recipient can open it.
+/// It carries the key of the sender, checked by the recipient against the known one.
+#[derive(Serialize, Deserialize)]
+pub struct Sealed {
+    pub sender_key: PublicKey,
+    pub nonce: [u8; 12],
+    pub data: Vec<u8>,
+}
+
+/// Room message stored by the server.
+/// `id` increases with each message of the server, `date` is in unix milliseconds.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub date: i64,
+    pub user: String,
+    pub content: String,
+}
+
+/// What the server did with a whisper or a file sent to a user that is not online.
+#[derive(Serialize, Deserialize)]
+pub enum DeliveryStatus {
+    /// Kept until the user logs on again.
+    Queued,
+    /// The server never saw the user.
+    UnknownUser,
+    Rejected(String),
+}
 
 #[derive(Serialize, Deserialize)]
 pub enum Chunk {
-    Data(Vec<u8>),
+    /// Proposes the file before sending it, with its size and hash.
+    /// The data is only sent once the recipient accepts it.
+    Offer(Sealed),
+    /// Data at an offset of the file.
+    Data(u64, Sealed),
     Error,
-    End,
+    /// Sent after the data, with the size and the hash of the whole file.
+    End(Sealed),
+    /// The sender cancelled the transfer, the received data can be removed.
+    Cancel,
+    /// Sent by the server: the transfer was dropped, the recipient was not receiving it fast enough.
+    Dropped,
 }
 
+/// Answer of the recipient of a file to its sender.
+#[derive(Serialize, Deserialize)]
+pub enum Receipt {
+    /// The offer of the file was accepted, by a client with these capabilities.
+    Accepted(Capabilities),
+    /// The offer of the file was rejected.
+    Rejected,
+    /// Every byte before the offset was received.
+    Received(u64),
+    /// The whole file was received and its hash matches.
+    Completed,
+    /// The file can not be received, the sender must stop.
+    Failed(String),
+    /// Sent by the server: the chunk at the offset is kept until the recipient logs on.
+    /// A kept offer is answered with `Queued(0)`, the recipient answers it once online.
+    Queued(u64),
+    /// The recipient cancelled the transfer, the sender must stop.
+    Cancelled,
+    /// Sent by the server: too much data is waiting for the recipient,
+    /// the chunks from the offset were not relayed. The sender waits to be released.
+    Held(u64),
+    /// Sent by the server: the recipient caught up, the sender goes on from the offset.
+    Released(u64),
+}
+
+/// Rates a client can send at, sent by the server after logging on.
+/// The messages over the limits are delayed, a client that keeps sending them is disconnected.
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+pub struct RateLimits {
+    /// Messages by second, the file chunks and receipts are only limited by `bytes`.
+    pub messages: Option<u32>,
+    /// Bytes by second, of any message.
+    pub bytes: Option<u32>,
+}
+
+impl RateLimits {
+    /// Time to wait after sending a message of `size` bytes to keep under the bytes limit.
+    pub fn pace(&self, size: usize) -> Duration {
+        match self.bytes {
+            Some(bytes) => Duration::from_secs_f64(size as f64 / bytes as f64),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+/// Proof of identity sent with `NetMessage::Logon`.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Credential {
+    Anonymous,
+    Password(String),
+    Token(String),
+}
+
+/// Messages exchanged between the clients and the server.
+/// The sender of the user variants is always stamped by the server,
+/// clients send the corresponding `Send*` variant without their name.
 #[derive(Serialize, Deserialize)]
 pub enum NetMessage {
+    // The two first variants keep their encoding in every version.
+    /// First message of a connection: the client sends its version and capabilities,
+    /// the server answers with the capabilities enabled for the connection.
+    Handshake(u32, Capabilities),
+    /// The server does not speak the version of the client, it must not connect again.
+    Incompatible(String),
     Hi(String),
     Bye(String),
-    BBB(String),
-    UserMessage(String, String),
-    WhisperMessage(String, String, String),
-    UserData(String, String, String, Chunk),
-}
\ No newline at end of file
+    /// Heartbeat with a timestamp of the client in milliseconds.
+    /// The server sends it back, so the client measures the round trip time.
+    BBB(u64),
+    // client -> server
+    Logon(String, Credential),
+    Nick(String),
+    SendMessage(MessageId, String),
+    PublishKey(PublicKey),
+    RequestKey(String),
+    SendWhisper(MessageId, String, Sealed),
+    SendData(String, TransferId, Chunk),
+    SendReceipt(String, TransferId, Receipt),
+    JoinRoom(String),
+    LeaveRoom,
+    ListRooms,
+    RequestHistory(Option<u64>),
+    RequestRoster,
+    /// Measure the latency with another user, with a timestamp of the sender in milliseconds.
+    SendPing(String, u64),
+    SendPong(String, u64),
+    /// Sent by the recipient of a whisper to its sender.
+    ConfirmDelivery(String, MessageId),
+    // server -> client
+    LogonAccepted(String),
+    LogonRejected(String),
+    NickRejected(String),
+    Renamed(String, String),
+    UserMessage(u64, String, String),
+    UserKey(String, Option<PublicKey>),
+    WhisperMessage(MessageId, String, String, Sealed),
+    UserData(String, String, TransferId, Chunk),
+    UserReceipt(String, TransferId, Receipt),
+    UserPing(String, u64),
+    UserPong(String, u64),
+    RoomJoined(String),
+    /// The room can not be joined, with the reason.
+    RoomRejected(String, String),
+    RoomList(Vec<(String, usize)>),
+    History(String, Vec<HistoryEntry>),
+    /// Users online, the next changes are pushed as `Hi`, `Bye` and `Renamed`.
+    Roster(Vec<String>),
+    /// Status of a whisper, or of a file without id, sent to a user that is not online.
+    /// Also rejects the room messages of muted users, with the room as the recipient.
+    Delivery(String, Option<MessageId>, DeliveryStatus),
+    /// The server received the message.
+    Ack(MessageId),
+    /// The recipient received the whisper.
+    Delivered(String, MessageId),
+    /// Announcement of the server administrator.
+    Notice(String),
+    /// The server closes the connection, the client must not connect again by itself.
+    Kicked(String),
+    Limits(RateLimits),
+    /// The messages of the client are delayed for going over the limits.
+    Throttled(String),
+}
+
+/// Checks that a room name is well formed, the server refuses to create the others.
+pub fn check_room_name(room: &str) -> Result<()> {
+    if room.is_empty() {
+        Err("the room name can not be empty".into())
+    }
+    else if room.chars().count() > MAX_ROOM_NAME_LEN {
+        Err(format!("the room name can not be longer than {} characters", MAX_ROOM_NAME_LEN).into())
+    }
+    else if room.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        Err("the room name can not contain spaces".into())
+    }
+    else {
+        Ok(())
+    }
+}
+
+fn check_string(what: &str, string: &str) -> Result<()> {
+    match string.len() > MAX_STRING_LEN {
+        true => Err(format!("{} of {} bytes, the limit is {}", what, string.len(), MAX_STRING_LEN).into()),
+        false => Ok(()),
+    }
+}
+
+fn check_strings<'a>(what: &str, strings: impl IntoIterator<Item = &'a String>) -> Result<()> {
+    strings.into_iter().try_for_each(|string| check_string(what, string))
+}
+
+fn check_sealed(sealed: &Sealed) -> Result<()> {
+    match sealed.data.len() > MAX_SEALED_LEN {
+        true => Err(format!("sealed data of {} bytes, the limit is {}", sealed.data.len(), MAX_SEALED_LEN).into()),
+        false => Ok(()),
+    }
+}
+
+fn check_chunk(chunk: &Chunk) -> Result<()> {
+    match chunk {
+        Chunk::Offer(sealed) | Chunk::Data(_, sealed) | Chunk::End(sealed) => check_sealed(sealed),
+        Chunk::Error | Chunk::Cancel | Chunk::Dropped => Ok(()),
+    }
+}
+
+fn check_receipt(receipt: &Receipt) -> Result<()> {
+    match receipt {
+        Receipt::Failed(reason) => check_string("text", reason),
+        Receipt::Accepted(_) | Receipt::Rejected | Receipt::Completed | Receipt::Cancelled => Ok(()),
+        Receipt::Received(_) | Receipt::Queued(_) | Receipt::Held(_) | Receipt::Released(_) => Ok(()),
+    }
+}
+
+impl NetMessage {
+    /// Encodes the message for the network, compressed if the other side has `Capabilities::COMPRESSION`.
+    /// Only the messages with text are compressed, the sealed data does not compress.
+    pub fn encode(&self, compression: bool) -> Vec<u8> {
+        let frame = bincode::serialize(self).unwrap();
+        let compressible = matches!(
+            self,
+            NetMessage::SendMessage(..)
+                | NetMessage::UserMessage(..)
+                | NetMessage::History(..)
+                | NetMessage::RoomList(..)
+                | NetMessage::Roster(..)
+                | NetMessage::Notice(..)
+        );
+        match compression && compressible {
+            true => compression::compress_frame(frame),
+            false => frame,
+        }
+    }
+
+    /// Decodes a frame received from the network.
+    /// Fails if it is malformed, bigger than `limit` or if any string or data is over its limit.
+    pub fn decode(data: &[u8], limit: u64) -> Result<NetMessage> {
+        if data.len() as u64 > limit {
+            return Err(format!("frame of {} bytes, the limit is {}", data.len(), limit).into())
+        }
+        let data = compression::decompress_frame(data, limit as usize)?;
+        // The same encoding as `bincode::serialize`.
+        let message: NetMessage = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+            .with_limit(limit)
+            .deserialize(&data)?;
+        message.check_sizes()?;
+        Ok(message)
+    }
+
+    fn check_sizes(&self) -> Result<()> {
+        match self {
+            NetMessage::Hi(user)
+            | NetMessage::Bye(user)
+            | NetMessage::Nick(user)
+            | NetMessage::RequestKey(user)
+            | NetMessage::SendPing(user, _)
+            | NetMessage::SendPong(user, _)
+            | NetMessage::ConfirmDelivery(user, _)
+            | NetMessage::LogonAccepted(user)
+            | NetMessage::UserKey(user, _)
+            | NetMessage::UserPing(user, _)
+            | NetMessage::UserPong(user, _)
+            | NetMessage::Delivered(user, _) => check_string("name", user),
+            NetMessage::JoinRoom(room) | NetMessage::RoomJoined(room) => check_string("room", room),
+            NetMessage::RoomRejected(room, reason) => {
+                check_string("room", room)?;
+                check_string("text", reason)
+            }
+            NetMessage::SendMessage(_, text)
+            | NetMessage::LogonRejected(text)
+            | NetMessage::NickRejected(text)
+            | NetMessage::Notice(text)
+            | NetMessage::Kicked(text)
+            | NetMessage::Incompatible(text)
+            | NetMessage::Throttled(text) => check_string("text", text),
+            NetMessage::Logon(user, credential) => {
+                check_string("name", user)?;
+                match credential {
+                    Credential::Anonymous => Ok(()),
+                    Credential::Password(secret) | Credential::Token(secret) => check_string("credential", secret),
+                }
+            }
+            NetMessage::Renamed(old, new) => check_strings("name", [old, new]),
+            NetMessage::UserMessage(_, user, text) => {
+                check_string("name", user)?;
+                check_string("text", text)
+            }
+            NetMessage::SendWhisper(_, to, sealed) => {
+                check_string("name", to)?;
+                check_sealed(sealed)
+            }
+            NetMessage::WhisperMessage(_, from, to, sealed) => {
+                check_strings("name", [from, to])?;
+                check_sealed(sealed)
+            }
+            NetMessage::SendData(to, _, chunk) => {
+                check_string("name", to)?;
+                check_chunk(chunk)
+            }
+            NetMessage::UserData(from, to, _, chunk) => {
+                check_strings("name", [from, to])?;
+                check_chunk(chunk)
+            }
+            NetMessage::SendReceipt(user, _, receipt) | NetMessage::UserReceipt(user, _, receipt) => {
+                check_string("name", user)?;
+                check_receipt(receipt)
+            }
+            NetMessage::RoomList(rooms) => check_strings("room", rooms.iter().map(|(room, _)| room)),
+            NetMessage::History(room, entries) => {
+                check_string("room", room)?;
+                entries.iter().try_for_each(|entry| {
+                    check_string("name", &entry.user)?;
+                    check_string("text", &entry.content)
+                })
+            }
+            NetMessage::Roster(users) => check_strings("name", users),
+            NetMessage::Delivery(user, _, status) => {
+                check_string("name", user)?;
+                match status {
+                    DeliveryStatus::Rejected(reason) => check_string("text", reason),
+                    DeliveryStatus::Queued | DeliveryStatus::UnknownUser => Ok(()),
+                }
+            }
+            NetMessage::Handshake(_, _)
+            | NetMessage::BBB(_)
+            | NetMessage::PublishKey(_)
+            | NetMessage::LeaveRoom
+            | NetMessage::ListRooms
+            | NetMessage::RequestHistory(_)
+            | NetMessage::RequestRoster
+            | NetMessage::Ack(_)
+            | NetMessage::Limits(_) => Ok(()),
+        }
+    }
+}
@@ -1,10 +1,18 @@
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, TimeZone};
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+use crate::e2e::KeyRing;
+use crate::message::{Capabilities, HistoryEntry, MessageId, RateLimits, Receipt, TransferId};
+use crate::transfer::{sanitize_file_name, Manifest, OFFER_TIMEOUT};
+use crate::util::Result;
 
 #[derive(PartialEq)]
 pub enum SystemMessageType {
     Info,
     Warning,
     Error,
+    /// Sent by the server administrator.
+    Notice,
 }
 
 #[derive(PartialEq)]
@@ -12,6 +20,124 @@ pub enum ProgressState {
     Started(u64),
     Working(u64, u64),
     Completed,
+    Paused(u64, u64),
+    Cancelled(u64, u64),
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum TransferDirection {
+    Sending,
+    Receiving,
+}
+
+#[derive(PartialEq, Clone)]
+pub enum TransferStatus {
+    /// Waiting for the recipient to accept the offer.
+    Offered,
+    Active,
+    /// Stopped by the user until it is resumed, only for the files being sent.
+    Paused,
+    /// Sent to an offline recipient, kept by the server until it logs on.
+    Queued,
+    Completed,
+    Failed(String),
+    Cancelled,
+}
+
+/// File sent to or received from another user, listed in the transfers panel.
+pub struct Transfer {
+    pub id: usize,
+    pub direction: TransferDirection,
+    pub user: String,
+    /// Id of the transfer on the network.
+    pub transfer_id: TransferId,
+    pub file_name: String,
+    pub size: u64,
+    /// Bytes received by the recipient.
+    pub done: u64,
+    pub status: TransferStatus,
+    // Time the first data was sent, and bytes done before it.
+    started: Option<(Instant, u64)>,
+    // Bytes of data sent or received, before and after compressing them.
+    raw: u64,
+    packed: u64,
+    // Entry of the progress bar in the messages.
+    progress: usize,
+}
+
+impl Transfer {
+    pub fn is_running(&self) -> bool {
+        matches!(self.status, TransferStatus::Offered | TransferStatus::Active | TransferStatus::Paused)
+    }
+
+    /// Bytes by second since the data started to be sent.
+    pub fn speed(&self) -> Option<f64> {
+        let (started, done) = self.started?;
+        let elapsed = started.elapsed().as_secs_f64();
+        match elapsed > 0.0 && self.done > done {
+            true => Some((self.done - done) as f64 / elapsed),
+            false => None,
+        }
+    }
+
+    /// Size of the compressed data compared to the original one, if it was compressed.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        match self.packed < self.raw {
+            true => Some(self.packed as f64 / self.raw as f64),
+            false => None,
+        }
+    }
+
+    /// Time remaining at the current speed.
+    pub fn remaining(&self) -> Option<Duration> {
+        let speed = self.speed().filter(|_| self.status == TransferStatus::Active)?;
+        Some(Duration::from_secs_f64((self.size - self.done) as f64 / speed))
+    }
+}
+
+/// Answer to a file offered by another user.
+#[derive(PartialEq, Clone, Copy)]
+pub enum OfferStatus {
+    Pending,
+    Accepted,
+    /// Accepted by the `accept_files_from` list of the config.
+    AutoAccepted,
+    Rejected,
+    Expired,
+    /// The sender cancelled the transfer.
+    Withdrawn,
+}
+
+/// Files offered by another user, until they are answered and their transfer starts.
+struct FileOffer {
+    manifest: Manifest,
+    status: OfferStatus,
+    // Time of the offer or of its acceptance.
+    date: Instant,
+    // Entry of the offer in the messages.
+    message: usize,
+}
+
+/// Delivery of a message sent by the user.
+#[derive(PartialEq, Clone, Copy)]
+pub enum MessageStatus {
+    Sending,
+    /// Received by the server.
+    Sent,
+    /// Received by the recipient of a whisper.
+    Delivered,
+    Failed,
+}
+
+#[derive(Default)]
+pub enum ConnectionStatus {
+    #[default]
+    Connecting,
+    Online,
+    /// Waiting the duration before connecting again.
+    Offline(Duration),
+    /// Disconnected by the server, it does not connect again.
+    Closed,
 }
 
 pub enum MessageType {
@@ -21,17 +147,36 @@ pub enum MessageType {
     PrivateText(String),
     System(String, SystemMessageType),
     Progress(ProgressState),
+    /// Files offered by the user of the message: name, size, number of files unless it is a single file, and answer.
+    Offer(String, u64, Option<usize>, OfferStatus),
+    /// Separates the replayed history of a room from the live messages.
+    HistoryEnd,
 }
 
 pub struct ChatMessage {
     pub date: DateTime<Local>,
     pub user: String,
     pub message_type: MessageType,
+    /// Sent before joining the room, replayed from the server history.
+    pub replayed: bool,
+    /// Id and status of the messages sent by the user.
+    pub sent: Option<(MessageId, MessageStatus)>,
 }
 
 impl ChatMessage {
     pub fn new(user: String, message_type: MessageType) -> ChatMessage {
-        ChatMessage { date: Local::now(), user, message_type }
+        ChatMessage { date: Local::now(), user, message_type, replayed: false, sent: None }
+    }
+
+    pub fn replayed(entry: HistoryEntry) -> ChatMessage {
+        let date = Local.timestamp_millis_opt(entry.date).single().unwrap_or_else(Local::now);
+        ChatMessage {
+            date,
+            user: entry.user,
+            message_type: MessageType::Text(entry.content),
+            replayed: true,
+            sent: None,
+        }
     }
 }
 
@@ -41,6 +186,26 @@ pub struct State {
     scroll_messages_view: usize,
     input: Vec<char>,
     input_cursor: usize,
+    user_name: Option<String>,
+    connection: ConnectionStatus,
+    latency: Option<Duration>,
+    rate_limits: RateLimits,
+    capabilities: Option<Capabilities>,
+    roster: BTreeSet<String>,
+    hide_roster: bool,
+    room: Option<String>,
+    key_ring: KeyRing,
+    // Last receipts of the files being sent, by recipient and transfer.
+    receipts: HashMap<(String, TransferId), Receipt>,
+    // Files offered by other users, by sender and transfer id.
+    offers: HashMap<(String, TransferId), FileOffer>,
+    transfers: Vec<Transfer>,
+    show_transfers: bool,
+    // Index of the transfer selected in the transfers panel.
+    selected_transfer: usize,
+    // Entry of the last input written by the user.
+    input_message: Option<usize>,
+    last_message_id: MessageId,
     pub stop_stream: bool,
 }
 
@@ -66,6 +231,374 @@ impl State {
         self.scroll_messages_view
     }
 
+    /// Name accepted by the server, `None` while not logged on.
+    pub fn user_name(&self) -> Option<&str> {
+        self.user_name.as_deref()
+    }
+
+    pub fn set_user_name(&mut self, user_name: Option<String>) {
+        self.user_name = user_name;
+    }
+
+    pub fn connection(&self) -> &ConnectionStatus {
+        &self.connection
+    }
+
+    pub fn set_connection(&mut self, connection: ConnectionStatus) {
+        self.connection = connection;
+    }
+
+    /// Round trip time with the server, measured with the heartbeats.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    pub fn set_latency(&mut self, latency: Option<Duration>) {
+        self.latency = latency;
+    }
+
+    /// Features enabled for the connection, `None` until the server answers the handshake.
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        self.capabilities
+    }
+
+    pub fn set_capabilities(&mut self, capabilities: Option<Capabilities>) {
+        self.capabilities = capabilities;
+    }
+
+    /// Whether the frames sent to the server can be compressed.
+    pub fn compression(&self) -> bool {
+        self.capabilities.is_some_and(|capabilities| capabilities.contains(Capabilities::COMPRESSION))
+    }
+
+    /// Limits of the server, the long actions keep under them.
+    pub fn rate_limits(&self) -> RateLimits {
+        self.rate_limits
+    }
+
+    pub fn set_rate_limits(&mut self, rate_limits: RateLimits) {
+        self.rate_limits = rate_limits;
+    }
+
+    /// Users online, sorted by name.
+    pub fn roster(&self) -> &BTreeSet<String> {
+        &self.roster
+    }
+
+    pub fn set_roster(&mut self, users: Vec<String>) {
+        self.roster = users.into_iter().collect();
+    }
+
+    pub fn roster_add(&mut self, user: String) {
+        self.roster.insert(user);
+    }
+
+    pub fn roster_remove(&mut self, user: &str) {
+        self.roster.remove(user);
+    }
+
+    pub fn show_roster(&self) -> bool {
+        !self.hide_roster
+    }
+
+    pub fn toggle_roster(&mut self) {
+        self.hide_roster = !self.hide_roster;
+    }
+
+    pub fn room(&self) -> Option<&str> {
+        self.room.as_deref()
+    }
+
+    pub fn set_room(&mut self, room: String) {
+        self.room = Some(room);
+    }
+
+    pub fn key_ring_mut(&mut self) -> &mut KeyRing {
+        &mut self.key_ring
+    }
+
+    /// Keeps the most advanced receipt of a file sent to the user, a final one is never replaced.
+    pub fn set_receipt(&mut self, user: String, transfer_id: TransferId, receipt: Receipt) {
+        let entry = self.receipts.entry((user, transfer_id)).or_insert(Receipt::Received(0));
+        match (&*entry, &receipt) {
+            (Receipt::Received(current), Receipt::Received(offset)) if offset < current => (),
+            (Receipt::Queued(current), Receipt::Queued(offset)) if offset < current => (),
+            // The offset to send again from must not be lost.
+            (Receipt::Held(_), Receipt::Received(_)) | (Receipt::Released(_), Receipt::Received(_)) => (),
+            (Receipt::Accepted(_), _) | (Receipt::Received(_), _) | (Receipt::Queued(_), _) => *entry = receipt,
+            (Receipt::Held(_), _) | (Receipt::Released(_), _) => *entry = receipt,
+            (Receipt::Rejected, _) | (Receipt::Completed, _) | (Receipt::Failed(_), _) => (),
+            (Receipt::Cancelled, _) => (),
+        }
+    }
+
+    /// Last receipt of a file sent to the user, removed from the state.
+    pub fn take_receipt(&mut self, user: &str, transfer_id: TransferId) -> Option<Receipt> {
+        self.receipts.remove(&(user.to_string(), transfer_id))
+    }
+
+    /// Shows the offer of a file, replacing any previous offer of the same transfer.
+    pub fn add_offer(&mut self, from: String, transfer_id: TransferId, manifest: Manifest) {
+        let files = match manifest.entries.as_slice() {
+            [entry] if !entry.directory => None,
+            _ => Some(manifest.files()),
+        };
+        let size = manifest.digest.size;
+        let message_type = MessageType::Offer(sanitize_file_name(&manifest.name()), size, files, OfferStatus::Pending);
+        self.messages.push(ChatMessage::new(from.clone(), message_type));
+        let offer = FileOffer { manifest, status: OfferStatus::Pending, date: Instant::now(), message: self.messages.len() - 1 };
+        self.offers.insert((from, transfer_id), offer);
+    }
+
+    /// Answers a pending offer of the user, the file name can be omitted if there is only one.
+    /// Returns the transfer of the answered file.
+    pub fn answer_offer(&mut self, from: &str, file_name: Option<&str>, status: OfferStatus) -> Result<TransferId> {
+        self.expire_offers();
+        let mut pending = self
+            .offers
+            .iter()
+            .filter(|((user, _), offer)| {
+                // The name can be the shown one, that is sanitized.
+                let name = offer.manifest.name();
+                let same_name = |file_name| name == file_name || sanitize_file_name(&name) == file_name;
+                user == from && file_name.is_none_or(same_name) && offer.status == OfferStatus::Pending
+            })
+            .map(|((_, transfer_id), _)| *transfer_id);
+        let transfer_id = match (pending.next(), pending.next()) {
+            (Some(transfer_id), None) => transfer_id,
+            (Some(_), Some(_)) => return Err(format!("'{}' offered several files, give the file name", from).into()),
+            (None, _) => return Err(format!("No pending file offered by '{}'", from).into()),
+        };
+        self.set_offer_status(from, transfer_id, status);
+        Ok(transfer_id)
+    }
+
+    /// Answers the offer of a transfer, a rejected one is forgotten.
+    pub fn set_offer_status(&mut self, from: &str, transfer_id: TransferId, status: OfferStatus) {
+        let key = (from.to_string(), transfer_id);
+        if let Some(offer) = self.offers.get_mut(&key) {
+            offer.status = status;
+            offer.date = Instant::now();
+            let message = offer.message;
+            if status == OfferStatus::Rejected {
+                self.offers.remove(&key);
+            }
+            self.set_offer_message_status(message, status);
+        }
+    }
+
+    /// Files of an accepted offer, removed from the state when their transfer starts.
+    pub fn take_accepted_offer(&mut self, from: &str, transfer_id: TransferId) -> Option<Manifest> {
+        let key = (from.to_string(), transfer_id);
+        match self.offers.get(&key)?.status {
+            OfferStatus::Accepted | OfferStatus::AutoAccepted => Some(self.offers.remove(&key)?.manifest),
+            _ => None,
+        }
+    }
+
+    /// Forgets an offer whose sender cancelled the transfer, returns whether it was still there.
+    pub fn withdraw_offer(&mut self, from: &str, transfer_id: TransferId) -> bool {
+        match self.offers.remove(&(from.to_string(), transfer_id)) {
+            Some(offer) => {
+                self.set_offer_message_status(offer.message, OfferStatus::Withdrawn);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forgets the offers not answered in time, and the accepted ones whose transfer never started.
+    pub fn expire_offers(&mut self) {
+        let mut expired = Vec::new();
+        self.offers.retain(|_, offer| {
+            if offer.date.elapsed() <= OFFER_TIMEOUT {
+                return true
+            }
+            if offer.status == OfferStatus::Pending {
+                expired.push(offer.message);
+            }
+            false
+        });
+        for message in expired {
+            self.set_offer_message_status(message, OfferStatus::Expired);
+        }
+    }
+
+    /// Adds a transfer to the transfers panel with its progress bar, returns its id.
+    pub fn add_transfer(
+        &mut self,
+        direction: TransferDirection,
+        user: &str,
+        transfer_id: TransferId,
+        file_name: &str,
+        size: u64,
+    ) -> usize {
+        let (label, status) = match direction {
+            TransferDirection::Sending => (format!("Sending '{}'", file_name), TransferStatus::Offered),
+            TransferDirection::Receiving => (format!("Receiving '{}'", file_name), TransferStatus::Active),
+        };
+        self.messages.push(ChatMessage::new(label, MessageType::Progress(ProgressState::Started(size))));
+        let id = self.transfers.len();
+        self.transfers.push(Transfer {
+            id,
+            direction,
+            user: user.to_string(),
+            transfer_id,
+            file_name: file_name.to_string(),
+            size,
+            done: 0,
+            status,
+            started: None,
+            raw: 0,
+            packed: 0,
+            progress: self.messages.len() - 1,
+        });
+        id
+    }
+
+    pub fn transfers(&self) -> &[Transfer] {
+        &self.transfers
+    }
+
+    pub fn transfer(&self, id: usize) -> &Transfer {
+        &self.transfers[id]
+    }
+
+    /// Transfer given by its number in the transfers panel, or by the name of a running one.
+    pub fn lookup_transfer(&self, reference: &str) -> Result<usize> {
+        if let Ok(number) = reference.trim_start_matches('#').parse::<usize>() {
+            return match number {
+                1.. if number <= self.transfers.len() => Ok(number - 1),
+                _ => Err(format!("No transfer #{}", number).into()),
+            }
+        }
+        let mut running = self
+            .transfers
+            .iter()
+            .filter(|transfer| transfer.is_running() && transfer.file_name == reference);
+        match (running.next(), running.next()) {
+            (Some(transfer), None) => Ok(transfer.id),
+            (Some(_), Some(_)) => Err(format!("Several transfers of '{}' are running, give its number", reference).into()),
+            (None, _) => Err(format!("No running transfer of '{}'", reference).into()),
+        }
+    }
+
+    /// Running transfer with the user.
+    pub fn find_transfer(&self, direction: TransferDirection, user: &str, transfer_id: TransferId) -> Option<usize> {
+        self.transfers
+            .iter()
+            .rev()
+            .find(|transfer| {
+                transfer.direction == direction
+                    && transfer.user == user
+                    && transfer.transfer_id == transfer_id
+                    && transfer.is_running()
+            })
+            .map(|transfer| transfer.id)
+    }
+
+    /// Name of the files offered or being sent by the user, only known from their sealed offer.
+    pub fn incoming_name(&self, from: &str, transfer_id: TransferId) -> Option<String> {
+        match self.offers.get(&(from.to_string(), transfer_id)) {
+            Some(offer) => Some(offer.manifest.name()),
+            None => self
+                .find_transfer(TransferDirection::Receiving, from, transfer_id)
+                .map(|id| self.transfers[id].file_name.clone()),
+        }
+    }
+
+    /// Updates the bytes received by the recipient, they never go back nor past the size.
+    pub fn update_transfer(&mut self, id: usize, done: u64) {
+        let transfer = &mut self.transfers[id];
+        let done = done.min(transfer.size);
+        if done <= transfer.done {
+            return
+        }
+        transfer.started.get_or_insert((Instant::now(), transfer.done));
+        transfer.done = done;
+        self.update_progress_message(id);
+    }
+
+    /// Counts the data of a chunk, with the size it was packed to.
+    pub fn count_transfer_data(&mut self, id: usize, raw: u64, packed: u64) {
+        let transfer = &mut self.transfers[id];
+        transfer.raw += raw;
+        transfer.packed += packed;
+    }
+
+    /// A finished transfer keeps its status.
+    pub fn set_transfer_status(&mut self, id: usize, status: TransferStatus) {
+        let transfer = &mut self.transfers[id];
+        if transfer.is_running() {
+            // The speed is measured again after a pause.
+            if transfer.status == TransferStatus::Paused {
+                transfer.started = None;
+            }
+            transfer.status = status;
+            self.update_progress_message(id);
+        }
+    }
+
+    /// Pauses, resumes or cancels a transfer as asked by the user.
+    /// The sending action and the receiving client notice the new status.
+    pub fn control_transfer(&mut self, id: usize, status: TransferStatus) -> Result<()> {
+        let transfer = &self.transfers[id];
+        if !transfer.is_running() {
+            return Err(format!("The transfer of '{}' is already over", transfer.file_name).into())
+        }
+        match (&status, &transfer.status) {
+            (TransferStatus::Paused, _) if transfer.direction == TransferDirection::Receiving => {
+                return Err("Only the files being sent can be paused".into())
+            }
+            (TransferStatus::Paused, TransferStatus::Offered) => {
+                return Err(format!("'{}' is not accepted yet", transfer.file_name).into())
+            }
+            (TransferStatus::Paused, TransferStatus::Paused) => {
+                return Err(format!("'{}' is already paused", transfer.file_name).into())
+            }
+            (TransferStatus::Active, current) if *current != TransferStatus::Paused => {
+                return Err(format!("'{}' is not paused", transfer.file_name).into())
+            }
+            _ => (),
+        }
+        self.set_transfer_status(id, status);
+        Ok(())
+    }
+
+    pub fn show_transfers(&self) -> bool {
+        self.show_transfers
+    }
+
+    pub fn toggle_transfers(&mut self) {
+        self.show_transfers = !self.show_transfers;
+    }
+
+    /// Transfer selected in the transfers panel.
+    pub fn selected_transfer(&self) -> Option<&Transfer> {
+        self.transfers.get(self.selected_transfer)
+    }
+
+    /// Moves the selection of the transfers panel, wrapping around.
+    pub fn select_transfer(&mut self, next: bool) {
+        let count = self.transfers.len();
+        if count > 0 {
+            self.selected_transfer = match next {
+                true => (self.selected_transfer + 1) % count,
+                false => (self.selected_transfer + count - 1) % count,
+            };
+        }
+    }
+
+    fn set_offer_message_status(&mut self, index: usize, status: OfferStatus) {
+        if let MessageType::Offer(_, _, _, current) = &mut self.messages[index].message_type {
+            *current = status;
+        }
+    }
+
+    pub fn set_key_ring(&mut self, key_ring: KeyRing) {
+        self.key_ring = key_ring;
+    }
+
     pub fn input(&self) -> &[char] {
         &self.input
     }
@@ -160,6 +693,38 @@ impl State {
         self.messages.push(message);
     }
 
+    /// Adds the echo of the user input, the action it produces can track it with `track_input_message`.
+    pub fn add_input_message(&mut self, message: ChatMessage) {
+        self.messages.push(message);
+        self.input_message = Some(self.messages.len() - 1);
+    }
+
+    /// Gives an id to the last input entry, that is sent as a message.
+    /// The id is unique, also among the ones given before restarting.
+    pub fn track_input_message(&mut self) -> MessageId {
+        let now = Local::now().timestamp_micros() as MessageId;
+        self.last_message_id = now.max(self.last_message_id + 1);
+        if let Some(index) = self.input_message {
+            self.messages[index].sent = Some((self.last_message_id, MessageStatus::Sending));
+        }
+        self.last_message_id
+    }
+
+    /// Updates the status of a sent message, the status never goes back.
+    pub fn update_message_status(&mut self, id: MessageId, status: MessageStatus) {
+        let message = self.messages.iter_mut().rev().find(|message| matches!(message.sent, Some((sent, _)) if sent == id));
+        if let Some(ChatMessage { sent: Some((_, current)), .. }) = message {
+            let forward = match *current {
+                MessageStatus::Sending => true,
+                MessageStatus::Sent => status == MessageStatus::Delivered,
+                MessageStatus::Delivered | MessageStatus::Failed => false,
+            };
+            if forward {
+                *current = status;
+            }
+        }
+    }
+
     pub fn add_system_warn_message(&mut self, content: String) {
         let message_type = MessageType::System(content, SystemMessageType::Warning);
         let message = ChatMessage::new("Termchat: ".into(), message_type);
@@ -178,32 +743,24 @@ impl State {
         self.messages.push(message);
     }
 
-    pub fn add_progress_message(&mut self, file_name: &str, total: u64) -> usize {
-        let message = ChatMessage::new(
-            format!("Sending '{}'", file_name),
-            MessageType::Progress(ProgressState::Started(total)),
-        );
+    pub fn add_server_notice_message(&mut self, content: String) {
+        let message_type = MessageType::System(content, SystemMessageType::Notice);
+        let message = ChatMessage::new("Server: ".into(), message_type);
         self.messages.push(message);
-        self.messages.len() - 1
-    }
-
-    pub fn progress_message_update(&mut self, index: usize, increment: u64) {
-        match &mut self.messages[index].message_type {
-            MessageType::Progress(ref mut state) => {
-                *state = match state {
-                    ProgressState::Started(total) => ProgressState::Working(*total, increment),
-                    ProgressState::Working(total, current) => {
-                        let new_current = *current + increment;
-                        if new_current == *total {
-                            ProgressState::Completed
-                        }
-                        else {
-                            ProgressState::Working(*total, new_current)
-                        }
-                    }
-                    ProgressState::Completed => ProgressState::Completed,
-                };
-            }
+    }
+
+    fn update_progress_message(&mut self, id: usize) {
+        let transfer = &self.transfers[id];
+        let (total, current) = (transfer.size, transfer.done);
+        let progress = match transfer.status {
+            TransferStatus::Paused => ProgressState::Paused(total, current),
+            TransferStatus::Cancelled => ProgressState::Cancelled(total, current),
+            _ if current == total => ProgressState::Completed,
+            _ if current == 0 => ProgressState::Started(total),
+            _ => ProgressState::Working(total, current),
+        };
+        match &mut self.messages[transfer.progress].message_type {
+            MessageType::Progress(state) => *state = progress,
             _ => panic!("Must be a Progress MessageType"),
         }
     }
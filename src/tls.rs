@@ -0,0 +1,279 @@
+// TLS support for the FramedTcp transport.
+// message-io has no encrypted transport, so the TLS connection is terminated in a thread
+// that forwards the plain bytes to the node through a loopback socket.
+use crate::util::Result;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, ClientConnection, Connection, OwnedTrustAnchor, PrivateKey,
+    RootCertStore, ServerConfig, ServerConnection, ServerName};
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const BUFFER_SIZE: usize = 16384;
+
+/// Which server certificates the client accepts.
+pub enum Trust {
+    /// Certificates signed by a well known authority.
+    WebPki,
+    /// Certificates signed by any of the certificates of the PEM file,
+    /// the server certificate itself if it is self-signed.
+    CaFile(PathBuf),
+    /// Only the certificate with this sha256 fingerprint, whoever signed it.
+    Fingerprint(String),
+}
+
+/// Hex sha256 of the DER certificate, the value used by `Trust::Fingerprint`.
+pub fn fingerprint(certificate: &Certificate) -> String {
+    format!("{:x}", Sha256::digest(&certificate.0))
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.chars().filter(|c| *c != ':').collect::<String>().to_lowercase()
+}
+
+struct PinnedCertificate(String);
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.0 {
+            Ok(ServerCertVerified::assertion())
+        }
+        else {
+            Err(rustls::Error::General("the server certificate does not match the pinned fingerprint".into()))
+        }
+    }
+}
+
+pub fn load_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    let certificates = rustls_pemfile::certs(&mut reader)?;
+    if certificates.is_empty() {
+        return Err(format!("No certificates found in {}", path.display()).into())
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => (),
+        }
+    }
+    Err(format!("No private key found in {}", path.display()).into())
+}
+
+pub fn client_config(trust: &Trust) -> Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let config = match trust {
+        Trust::Fingerprint(fingerprint) => builder
+            .with_custom_certificate_verifier(Arc::new(PinnedCertificate(normalize_fingerprint(fingerprint))))
+            .with_no_client_auth(),
+        Trust::CaFile(path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in load_certificates(path)? {
+                roots.add(&certificate)?;
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+        Trust::WebPki => {
+            let mut roots = RootCertStore::empty();
+            roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    anchor.subject,
+                    anchor.spki,
+                    anchor.name_constraints,
+                )
+            }));
+            builder.with_root_certificates(roots).with_no_client_auth()
+        }
+    };
+    Ok(Arc::new(config))
+}
+
+pub fn server_config(certificate_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>> {
+    let certificates = load_certificates(certificate_path)?;
+    let key = load_private_key(key_path)?;
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?;
+    Ok(Arc::new(config))
+}
+
+/// Opens a TLS connection with the server and completes the handshake.
+/// Returns the loopback address the node must connect to with `Transport::FramedTcp`,
+/// the tunnel ends when any of both connections is closed.
+pub fn connect(server_addr: SocketAddr, server_name: &str, trust: &Trust) -> Result<SocketAddr> {
+    let server_name = ServerName::try_from(server_name).map_err(|_| format!("Invalid server name '{}'", server_name))?;
+    let mut connection = ClientConnection::new(client_config(trust)?, server_name)?;
+
+    let mut socket = TcpStream::connect_timeout(&server_addr, HANDSHAKE_TIMEOUT)?;
+    socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut socket)?;
+    }
+    socket.set_read_timeout(None)?;
+
+    let local_listener = TcpListener::bind("127.0.0.1:0")?;
+    let local_addr = local_listener.local_addr()?;
+    thread::Builder::new().name("termchat: tls tunnel".into()).spawn(move || {
+        if let Ok((local, _)) = local_listener.accept() {
+            tunnel(connection.into(), socket, local);
+        }
+    })?;
+    Ok(local_addr)
+}
+
+/// Real address of the peers connected through an `Acceptor`,
+/// indexed by the address the node sees for them.
+pub type PeerAddrs = Arc<Mutex<HashMap<SocketAddr, SocketAddr>>>;
+
+/// Accepts TLS connections and forwards each one to a local plain FramedTcp listener.
+pub struct Acceptor {
+    peer_addrs: PeerAddrs,
+}
+
+impl Acceptor {
+    pub fn spawn(public_addr: SocketAddr, backend_addr: SocketAddr, config: Arc<ServerConfig>) -> Result<Acceptor> {
+        let listener = TcpListener::bind(public_addr)?;
+        let peer_addrs = PeerAddrs::default();
+        let addrs = peer_addrs.clone();
+        thread::Builder::new().name("termchat: tls acceptor".into()).spawn(move || {
+            for socket in listener.incoming().flatten() {
+                let config = config.clone();
+                let addrs = addrs.clone();
+                let spawned = thread::Builder::new().name("termchat: tls tunnel".into()).spawn(move || {
+                    if let Err(e) = accept(socket, backend_addr, config, addrs) {
+                        println!("[TLS] {}", e);
+                    }
+                });
+                if let Err(e) = spawned {
+                    println!("[TLS] Unable to spawn a tunnel: {}", e);
+                }
+            }
+        })?;
+        Ok(Acceptor { peer_addrs })
+    }
+
+    /// Real address of a peer given the address of its endpoint in the node.
+    pub fn peer_addr(&self, endpoint_addr: SocketAddr) -> Option<SocketAddr> {
+        self.peer_addrs.lock().unwrap().get(&endpoint_addr).copied()
+    }
+}
+
+fn accept(mut socket: TcpStream, backend_addr: SocketAddr, config: Arc<ServerConfig>, peer_addrs: PeerAddrs) -> Result<()> {
+    let peer_addr = socket.peer_addr()?;
+    let mut connection = ServerConnection::new(config)?;
+    socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    while connection.is_handshaking() {
+        connection.complete_io(&mut socket).map_err(|e| format!("Handshake with {} failed: {}", peer_addr, e))?;
+    }
+    socket.set_read_timeout(None)?;
+
+    let backend = TcpStream::connect(backend_addr)?;
+    let endpoint_addr = backend.local_addr()?;
+    peer_addrs.lock().unwrap().insert(endpoint_addr, peer_addr);
+    tunnel(connection.into(), socket, backend);
+    peer_addrs.lock().unwrap().remove(&endpoint_addr);
+    Ok(())
+}
+
+/// Forwards the data between the encrypted and the plain sockets until one of them is closed.
+/// Only the calling thread writes into the encrypted socket, so the records are never reordered.
+fn tunnel(connection: Connection, encrypted: TcpStream, plain: TcpStream) {
+    let connection = Arc::new(Mutex::new(connection));
+    let decrypt = {
+        let connection = connection.clone();
+        let (encrypted, plain) = match (encrypted.try_clone(), plain.try_clone()) {
+            (Ok(encrypted), Ok(plain)) => (encrypted, plain),
+            _ => return,
+        };
+        thread::spawn(move || {
+            let _ = decrypt(&connection, &encrypted, &plain);
+            let _ = encrypted.shutdown(Shutdown::Both);
+            let _ = plain.shutdown(Shutdown::Both);
+        })
+    };
+
+    let _ = encrypt(&connection, &encrypted, &plain);
+    let _ = encrypted.shutdown(Shutdown::Both);
+    let _ = plain.shutdown(Shutdown::Both);
+    let _ = decrypt.join();
+}
+
+fn encrypt(connection: &Mutex<Connection>, mut encrypted: &TcpStream, mut plain: &TcpStream) -> std::io::Result<()> {
+    let mut buffer = [0; BUFFER_SIZE];
+    loop {
+        let size = plain.read(&mut buffer)?;
+        let mut records = Vec::new();
+        {
+            let mut connection = connection.lock().unwrap();
+            if size == 0 {
+                connection.send_close_notify();
+            }
+            else {
+                connection.writer().write_all(&buffer[..size])?;
+            }
+            while connection.wants_write() {
+                connection.write_tls(&mut records)?;
+            }
+        }
+        encrypted.write_all(&records)?;
+        if size == 0 {
+            return Ok(())
+        }
+    }
+}
+
+fn decrypt(connection: &Mutex<Connection>, mut encrypted: &TcpStream, mut plain: &TcpStream) -> std::io::Result<()> {
+    let mut buffer = [0; BUFFER_SIZE];
+    // The handshake could have read some data already.
+    let mut size = 0;
+    loop {
+        let mut data = Vec::new();
+        let closed = {
+            let mut connection = connection.lock().unwrap();
+            let mut records = &buffer[..size];
+            while !records.is_empty() {
+                connection.read_tls(&mut records)?;
+                connection
+                    .process_new_packets()
+                    .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+            }
+            match connection.reader().read_to_end(&mut data) {
+                Ok(_) => true,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => false,
+                Err(e) => return Err(e),
+            }
+        };
+        plain.write_all(&data)?;
+        if closed {
+            return Ok(())
+        }
+
+        size = encrypted.read(&mut buffer)?;
+        if size == 0 {
+            return Ok(())
+        }
+    }
+}
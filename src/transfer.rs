@@ -0,0 +1,484 @@
+use crate::message::{TransferId, MAX_SEALED_LEN};
+use crate::util::Result;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Time to answer an offer, after it the offer is forgotten by both users.
+pub const OFFER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Content of the sealed `Chunk::End`: the size and the sha256 hash of the whole data sent.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct FileDigest {
+    pub size: u64,
+    pub hash: [u8; 32],
+}
+
+impl FileDigest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.size.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.hash);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<FileDigest> {
+        if bytes.len() != 40 {
+            return Err("Malformed end of file".into())
+        }
+        let size = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let hash = bytes[8..].try_into().unwrap();
+        Ok(FileDigest { size, hash })
+    }
+
+    /// Reads the whole file from its start.
+    pub fn of_file(file: &mut File) -> Result<FileDigest> {
+        let mut hasher = FileHasher::default();
+        let mut data = vec![0; 65536];
+        file.seek(SeekFrom::Start(0))?;
+        loop {
+            let len = file.read(&mut data)?;
+            if len == 0 {
+                return Ok(hasher.digest())
+            }
+            hasher.update(hasher.hashed, &data[..len]);
+        }
+    }
+}
+
+/// File or directory of an offer.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Components of the path, the first one is the name of the file or directory given to `?send`.
+    pub path: Vec<String>,
+    pub directory: bool,
+    /// Bytes of the file, 0 for a directory.
+    pub size: u64,
+    /// Unix permissions of the owner, group and others.
+    pub mode: u32,
+}
+
+/// Content of the sealed `Chunk::Offer`: the files and directories sent, with the digest of their data.
+/// The data of the files is sent one after another, in the order of the entries.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub digest: FileDigest,
+    pub entries: Vec<Entry>,
+}
+
+impl Manifest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Manifest> {
+        let manifest: Manifest = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .reject_trailing_bytes()
+            .with_limit(MAX_SEALED_LEN as u64)
+            .deserialize(bytes)?;
+        if manifest.entries.iter().any(|entry| entry.path.is_empty() || entry.directory && entry.size > 0)
+            || !manifest.entries.iter().any(|entry| entry.path.len() == 1)
+        {
+            return Err("Malformed list of files".into())
+        }
+        let size = manifest.entries.iter().try_fold(0u64, |size, entry| size.checked_add(entry.size));
+        if size != Some(manifest.digest.size) {
+            return Err("The files do not match the offered size".into())
+        }
+        Ok(manifest)
+    }
+
+    /// Name of the transfer, only known by the sender and the recipient.
+    pub fn name(&self) -> String {
+        transfer_name(&self.entries)
+    }
+
+    /// Files, without the directories.
+    pub fn files(&self) -> usize {
+        self.entries.iter().filter(|entry| !entry.directory).count()
+    }
+}
+
+fn transfer_name(entries: &[Entry]) -> String {
+    let roots = entries.iter().filter(|entry| entry.path.len() == 1).collect::<Vec<_>>();
+    match roots.len() {
+        1 => roots[0].path[0].clone(),
+        len => format!("{} and {} more", roots[0].path[0], len - 1),
+    }
+}
+
+/// Files and directories given to `?send`, read as the data of their files one after another.
+pub struct OutgoingFiles {
+    entries: Vec<Entry>,
+    // Path of each entry.
+    sources: Vec<PathBuf>,
+    // Open file and its entry, the chunks are read in order.
+    current: Option<(usize, File)>,
+    // Data read for the manifest until now.
+    hasher: FileHasher,
+}
+
+impl OutgoingFiles {
+    /// Room left in a sealed offer for the list of files.
+    const MAX_MANIFEST_LEN: usize = MAX_SEALED_LEN - 1024;
+
+    /// Directories are sent with their whole content, the links to directories are not followed.
+    pub fn collect(paths: &[PathBuf]) -> Result<OutgoingFiles> {
+        let mut files = OutgoingFiles { entries: Vec::new(), sources: Vec::new(), current: None, hasher: FileHasher::default() };
+        let mut names = Vec::new();
+        for path in paths {
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| format!("Unable to read the file name of '{}'", path.display()))?;
+            if names.contains(&name) {
+                return Err(format!("Several files are named '{}'", name).into())
+            }
+            names.push(name);
+            files.add(path.clone(), vec![name.to_string()])?;
+        }
+        if bincode::serialized_size(&files.entries)? > Self::MAX_MANIFEST_LEN as u64 {
+            return Err(format!("Too many files, {} are given", files.entries.len()).into())
+        }
+        Ok(files)
+    }
+
+    fn add(&mut self, source: PathBuf, path: Vec<String>) -> Result<()> {
+        let metadata = std::fs::metadata(&source)?;
+        let directory = metadata.is_dir();
+        if !directory && !metadata.is_file() {
+            return Err(format!("'{}' is not a file or a directory", source.display()).into())
+        }
+        let size = if directory { 0 } else { metadata.len() };
+        self.entries.push(Entry { path: path.clone(), directory, size, mode: file_mode(&metadata) });
+        self.sources.push(source.clone());
+        if directory {
+            let mut children = std::fs::read_dir(&source)?.collect::<std::io::Result<Vec<_>>>()?;
+            children.sort_by_key(|child| child.file_name());
+            for child in children {
+                let is_link = child.file_type()?.is_symlink();
+                if is_link && child.path().is_dir() {
+                    continue
+                }
+                let name = child.file_name().into_string().map_err(|name| {
+                    format!("Unable to read the file name of '{}'", Path::new(&name).display())
+                })?;
+                let mut child_path = path.clone();
+                child_path.push(name);
+                self.add(child.path(), child_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Name of the transfer: the only file or directory given, or the first one.
+    pub fn name(&self) -> String {
+        transfer_name(&self.entries)
+    }
+
+    /// Bytes of all the files.
+    pub fn size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+
+    /// Reads the whole data, the files can change until then.
+    pub fn manifest(&mut self) -> Result<Manifest> {
+        loop {
+            if let Some(manifest) = self.hash(u64::MAX)? {
+                return Ok(manifest)
+            }
+        }
+    }
+
+    /// Reads at most `len` more bytes of the data, the manifest is returned once all of it was read.
+    pub fn hash(&mut self, len: u64) -> Result<Option<Manifest>> {
+        let size = self.size();
+        let end = self.hasher.hashed.saturating_add(len).min(size);
+        while self.hasher.hashed < end {
+            let offset = self.hasher.hashed;
+            let data = self.read(offset, (end - offset).min(65536) as usize)?;
+            self.hasher.update(offset, &data);
+        }
+        match self.hasher.hashed == size {
+            true => Ok(Some(Manifest { digest: self.hasher.digest(), entries: self.entries.clone() })),
+            false => Ok(None),
+        }
+    }
+
+    /// Data at an offset of the files one after another.
+    pub fn read(&mut self, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len);
+        let mut start = 0;
+        for (index, entry) in self.entries.iter().enumerate() {
+            if data.len() == len {
+                break
+            }
+            let end = start + entry.size;
+            let position = offset + data.len() as u64;
+            if position < end {
+                let file = match &mut self.current {
+                    Some((current, file)) if *current == index => file,
+                    current => &mut current.insert((index, File::open(&self.sources[index])?)).1,
+                };
+                let read = (len - data.len()).min((end - position) as usize);
+                let mut chunk = vec![0; read];
+                file.seek(SeekFrom::Start(position - start))?;
+                file.read_exact(&mut chunk)?;
+                data.extend_from_slice(&chunk);
+            }
+            start = end;
+        }
+        match data.len() == len {
+            true => Ok(data),
+            false => Err(std::io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+/// Only the permissions of the owner, group and others are applied, never setuid or setgid.
+#[cfg(unix)]
+fn set_file_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))
+}
+
+#[cfg(not(unix))]
+fn set_file_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    std::fs::set_permissions(path, permissions)
+}
+
+/// Hash of the data of a file, fed in order while it is sent or received.
+/// Data already hashed is ignored, so chunks sent again are not hashed twice.
+#[derive(Default)]
+pub struct FileHasher {
+    hasher: Sha256,
+    hashed: u64,
+}
+
+impl FileHasher {
+    /// Data after a gap is ignored too.
+    pub fn update(&mut self, offset: u64, data: &[u8]) {
+        let end = offset + data.len() as u64;
+        if offset <= self.hashed && end > self.hashed {
+            self.hasher.update(&data[(self.hashed - offset) as usize..]);
+            self.hashed = end;
+        }
+    }
+
+    /// Digest of the data hashed until now.
+    pub fn digest(&self) -> FileDigest {
+        FileDigest { size: self.hashed, hash: self.hasher.clone().finalize().into() }
+    }
+}
+
+/// Longest file name in bytes accepted by the usual file systems.
+const MAX_FILE_NAME_LEN: usize = 255;
+
+/// Makes a file name received from another user safe to be created in the download directory:
+/// no path separators, no parent or hidden files, no control characters and no reserved names.
+pub fn sanitize_file_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    // Room left for the number of a unique name.
+    let name = truncate(name.trim_matches(|c: char| c == '.' || c.is_whitespace()), MAX_FILE_NAME_LEN - 16);
+    match name.is_empty() {
+        true => String::from("file"),
+        false if is_reserved(&name) => format!("_{}", name),
+        false => name,
+    }
+}
+
+/// Names of devices on Windows, with any extension.
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default().to_ascii_uppercase();
+    let numbered = |prefix| stem.len() == 4 && stem.starts_with(prefix) && stem.ends_with(|c: char| c.is_ascii_digit());
+    ["CON", "PRN", "AUX", "NUL"].contains(&stem.as_str()) || numbered("COM") || numbered("LPT")
+}
+
+/// Longest prefix of the text that fits in `len` bytes.
+fn truncate(text: &str, len: usize) -> String {
+    let mut end = text.len().min(len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].to_string()
+}
+
+/// Path in the directory that is not used yet, adding a number to the name if needed: `name (1).ext`.
+pub fn unique_path(dir: &Path, file_name: &str) -> PathBuf {
+    let (stem, extension) = match file_name.rfind('.') {
+        Some(dot) if dot > 0 => file_name.split_at(dot),
+        _ => (file_name, ""),
+    };
+    let mut path = dir.join(file_name);
+    let mut number = 1;
+    while path.exists() {
+        path = dir.join(format!("{} ({}){}", stem, number, extension));
+        number += 1;
+    }
+    path
+}
+
+/// Files being received from another user, after accepting their offer.
+/// The chunks are written at their offset, so chunks sent again after a disconnection are harmless.
+/// The data is written to a hidden partial file, removed if the transfer does not complete.
+pub struct IncomingFile {
+    dir: PathBuf,
+    partial_path: PathBuf,
+    file: File,
+    offer: Manifest,
+    hasher: FileHasher,
+    completed: bool,
+}
+
+impl IncomingFile {
+    /// Creates the partial file in the directory, named after the sender, the files and the transfer.
+    /// The partial file of a transfer starting again from the beginning is replaced.
+    pub fn create(dir: &Path, from: &str, transfer_id: TransferId, offer: Manifest) -> Result<IncomingFile> {
+        std::fs::create_dir_all(dir)?;
+        let name = sanitize_file_name(&format!("{}-{}", from, offer.name()));
+        let partial_name = format!("{}-{:016x}", truncate(&name, MAX_FILE_NAME_LEN - 23), transfer_id);
+        let partial_path = dir.join(format!(".{}.part", partial_name));
+        // Read again to move the data to the received files.
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&partial_path)?;
+        Ok(IncomingFile {
+            dir: dir.to_path_buf(),
+            partial_path,
+            file,
+            offer,
+            hasher: FileHasher::default(),
+            completed: false,
+        })
+    }
+
+    /// Moves the verified files to their final names, returns the paths of the files and directories sent.
+    /// A name already used gets a number, the files inside a sent directory keep their names.
+    pub fn complete(mut self) -> Result<Vec<PathBuf>> {
+        if let [entry] = self.offer.entries.as_slice() {
+            if !entry.directory {
+                // A single file is only renamed.
+                let path = unique_path(&self.dir, &sanitize_file_name(&entry.path[0]));
+                std::fs::rename(&self.partial_path, &path)?;
+                self.completed = true;
+                set_file_mode(&path, entry.mode)?;
+                return Ok(vec![path])
+            }
+        }
+
+        // The partial file is removed once dropped.
+        let mut roots = HashMap::new();
+        let mut root_paths = Vec::new();
+        let mut directories = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        for entry in &self.offer.entries {
+            let mut components = entry.path.iter().map(|component| sanitize_file_name(component));
+            let root = components.next().unwrap();
+            let mut path = match roots.get(&entry.path[0]) {
+                Some(path) => PathBuf::clone(path),
+                None => {
+                    let path = unique_path(&self.dir, &root);
+                    roots.insert(entry.path[0].clone(), path.clone());
+                    root_paths.push(path.clone());
+                    path
+                }
+            };
+            path.extend(components);
+            if entry.directory {
+                std::fs::create_dir_all(&path)?;
+                directories.push((path, entry.mode));
+            }
+            else {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let mut file = File::create(&path)?;
+                if std::io::copy(&mut (&self.file).take(entry.size), &mut file)? != entry.size {
+                    return Err("the files are truncated".into())
+                }
+                set_file_mode(&path, entry.mode)?;
+            }
+        }
+        // Read only directories are restricted once their files are written.
+        for (path, mode) in directories.iter().rev() {
+            set_file_mode(path, *mode)?;
+        }
+        Ok(root_paths)
+    }
+
+    /// Accepted offer.
+    pub fn offer(&self) -> &Manifest {
+        &self.offer
+    }
+
+    /// Every byte before this offset was received.
+    pub fn received(&self) -> u64 {
+        self.hasher.hashed
+    }
+
+    /// Writes a chunk, ignored if there is missing data before it.
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        if offset.checked_add(data.len() as u64).is_none_or(|end| end > self.offer.digest.size) {
+            return Err("the file is bigger than offered".into())
+        }
+        if offset > self.received() {
+            return Ok(())
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        self.hasher.update(offset, data);
+        Ok(())
+    }
+
+    /// Checks the received data against the digest sent at the end, that must be the offered one.
+    /// `Ok(false)` means data is still missing.
+    pub fn verify(&self, digest: &FileDigest) -> Result<bool> {
+        if *digest != self.offer.digest {
+            return Err("the file changed since it was offered".into())
+        }
+        if self.received() < digest.size {
+            return Ok(false)
+        }
+        let received = self.hasher.digest();
+        if received.size != digest.size || received.hash != digest.hash {
+            return Err("the file is corrupted".into())
+        }
+        Ok(true)
+    }
+}
+
+impl Drop for IncomingFile {
+    fn drop(&mut self) {
+        if !self.completed {
+            let _ = std::fs::remove_file(&self.partial_path);
+        }
+    }
+}
@@ -1,7 +1,10 @@
 use crate::{config::Theme};
-use super::state::{ProgressState, State, MessageType, SystemMessageType};
+use super::state::{
+    ConnectionStatus, MessageStatus, OfferStatus, ProgressState, State, MessageType, SystemMessageType, Transfer,
+    TransferDirection, TransferStatus,
+};
 use super::commands::{CommandManager};
-use super::util::{split_each};
+use super::util::{self, split_each};
 
 use tui::backend::CrosstermBackend;
 use tui::layout::{Alignment, Constraint, Direction, Layout, Rect};
@@ -9,22 +12,165 @@ use tui::style::{Modifier, Style};
 use tui::text::{Span, Spans};
 use tui::widgets::{Block, Borders, Paragraph, Wrap};
 use tui::{Frame};
+use unicode_width::UnicodeWidthStr;
 
 use std::io::Write;
 
+// Enough for the longest user names.
+const ROSTER_WIDTH: u16 = 24;
+// Transfers listed at once in the transfers panel.
+const TRANSFERS_ROWS: usize = 6;
+
 pub fn draw(
     frame: &mut Frame<CrosstermBackend<impl Write>>,
     state: &State,
     chunk: Rect,
     theme: &Theme,
 ) {
+    let transfers_height = match state.show_transfers() {
+        true => state.transfers().len().clamp(1, TRANSFERS_ROWS) as u16 + 2,
+        false => 0,
+    };
     let chunks = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Min(0), Constraint::Length(6)].as_ref())
+        .constraints(
+            [Constraint::Min(0), Constraint::Length(transfers_height), Constraint::Length(6)].as_ref(),
+        )
         .split(chunk);
     
-    draw_messages_panel(frame, state, chunks[0], theme);
-    draw_input_panel(frame, state, chunks[1], theme);
+    if state.show_roster() {
+        let top_chunks = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(0), Constraint::Length(ROSTER_WIDTH)].as_ref())
+            .split(chunks[0]);
+        draw_messages_panel(frame, state, top_chunks[0], theme);
+        draw_roster_panel(frame, state, top_chunks[1], theme);
+    }
+    else {
+        draw_messages_panel(frame, state, chunks[0], theme);
+    }
+    if state.show_transfers() {
+        draw_transfers_panel(frame, state, chunks[1], theme);
+    }
+    draw_input_panel(frame, state, chunks[2], theme);
+}
+
+fn draw_roster_panel(
+    frame: &mut Frame<CrosstermBackend<impl Write>>,
+    state: &State,
+    chunk: Rect,
+    theme: &Theme,
+) {
+    let message_colors = &theme.message_colors;
+    let users = state
+        .roster()
+        .iter()
+        .map(|user| {
+            // Same color as the messages of the user.
+            let color = message_colors[user.len() % message_colors.len()];
+            let mut style = Style::default().fg(color);
+            if state.user_name() == Some(user.as_str()) {
+                style = style.add_modifier(Modifier::BOLD);
+            }
+            Spans::from(Span::styled(user.as_str(), style))
+        })
+        .collect::<Vec<_>>();
+
+    let title = format!("Online ({})", state.roster().len());
+    let roster_panel = Paragraph::new(users)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(Span::styled(title, Style::default().add_modifier(Modifier::BOLD))),
+        )
+        .style(Style::default().fg(theme.chat_panel_color))
+        .alignment(Alignment::Left);
+
+    frame.render_widget(roster_panel, chunk);
+}
+
+fn draw_transfers_panel(
+    frame: &mut Frame<CrosstermBackend<impl Write>>,
+    state: &State,
+    chunk: Rect,
+    theme: &Theme,
+) {
+    let transfers = state.transfers();
+    let selected = state.selected_transfer().map(|transfer| transfer.id);
+    // The selected transfer is always visible.
+    let first = selected.map_or(0, |selected| (selected + 1).saturating_sub(TRANSFERS_ROWS));
+    let rows = transfers
+        .iter()
+        .skip(first)
+        .take(TRANSFERS_ROWS)
+        .map(|transfer| {
+            let spans = transfer_row(transfer, theme);
+            match Some(transfer.id) == selected {
+                true => Spans::from(
+                    spans
+                        .into_iter()
+                        .map(|span| Span::styled(span.content, span.style.add_modifier(Modifier::REVERSED)))
+                        .collect::<Vec<_>>(),
+                ),
+                false => Spans::from(spans),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let title = format!("Transfers ({}) - Tab to select, Ctrl+X to cancel", transfers.len());
+    let transfers_panel = Paragraph::new(rows)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(Span::styled(title, Style::default().add_modifier(Modifier::BOLD))),
+        )
+        .style(Style::default().fg(theme.chat_panel_color))
+        .alignment(Alignment::Left);
+
+    frame.render_widget(transfers_panel, chunk);
+}
+
+fn transfer_row<'a>(transfer: &'a Transfer, theme: &Theme) -> Vec<Span<'a>> {
+    let (direction, user_label) = match transfer.direction {
+        TransferDirection::Sending => ("↑ ", "to"),
+        TransferDirection::Receiving => ("↓ ", "from"),
+    };
+    let percentage = match transfer.size {
+        0 => 100,
+        size => transfer.done * 100 / size,
+    };
+    let mut progress = format!(
+        " {}% {} / {}",
+        percentage,
+        util::format_size(transfer.done),
+        util::format_size(transfer.size),
+    );
+    if let Some(speed) = transfer.speed().filter(|_| transfer.status == TransferStatus::Active) {
+        progress.push_str(&format!(", {}/s", util::format_size(speed as u64)));
+    }
+    if let Some(ratio) = transfer.compression_ratio() {
+        progress.push_str(&format!(", compressed to {:.0}%", ratio * 100.0));
+    }
+    if let Some(remaining) = transfer.remaining() {
+        progress.push_str(&format!(", {} left", util::format_duration(remaining)));
+    }
+    let (status, status_color) = match &transfer.status {
+        TransferStatus::Offered => (String::from("offered"), theme.system_warning_color.1),
+        TransferStatus::Active => (String::from("active"), theme.progress_bar_color),
+        TransferStatus::Paused => (String::from("paused"), theme.system_warning_color.1),
+        TransferStatus::Queued => (String::from("queued by the server"), theme.system_info_color.1),
+        TransferStatus::Completed => (String::from("completed"), theme.system_info_color.1),
+        TransferStatus::Failed(reason) => (format!("failed: {}", reason), theme.system_error_color.1),
+        TransferStatus::Cancelled => (String::from("cancelled"), theme.date_color),
+    };
+    vec![
+        Span::raw(format!("#{} {}", transfer.id + 1, direction)),
+        Span::styled(&transfer.file_name, Style::default().add_modifier(Modifier::BOLD)),
+        Span::raw(format!(" {} {}", user_label, transfer.user)),
+        Span::styled(progress, Style::default().fg(theme.progress_bar_color)),
+        Span::raw(" - "),
+        Span::styled(status, Style::default().fg(status_color)),
+    ]
 }
 
 fn draw_messages_panel(
@@ -41,8 +187,11 @@ fn draw_messages_panel(
         .map(|message| {
         
             let color = message_colors[message.user.len() % message_colors.len()];
-            let date = message.date.format("%H:%M:%S ").to_string();
-            match &message.message_type {
+            let date = match message.replayed {
+                true => message.date.format("%d/%m %H:%M ").to_string(),
+                false => message.date.format("%H:%M:%S ").to_string(),
+            };
+            let mut spans = match &message.message_type {
                 MessageType::Connection => Spans::from(vec![
                     Span::styled(date, Style::default().fg(theme.date_color)),
                     Span::styled(&message.user, Style::default().fg(color)),
@@ -76,6 +225,7 @@ fn draw_messages_panel(
                         SystemMessageType::Info => theme.system_info_color,
                         SystemMessageType::Warning => theme.system_warning_color,
                         SystemMessageType::Error => theme.system_error_color,
+                        SystemMessageType::Notice => theme.server_notice_color,
                     };
                     Spans::from(vec![
                         Span::styled(date, Style::default().fg(theme.date_color)),
@@ -84,17 +234,62 @@ fn draw_messages_panel(
                     ])
                 }
                 MessageType::Progress(state) => {
-                    Spans::from(add_progress_bar(chunk.width, state, theme))
+                    Spans::from(add_progress_bar(chunk.width, &message.user, state, theme))
+                }
+                MessageType::Offer(file_name, size, files, status) => {
+                    let (answer, answer_color) = match status {
+                        OfferStatus::Pending => {
+                            (format!("?accept {} or ?reject {}", message.user, message.user), theme.system_warning_color.1)
+                        }
+                        OfferStatus::Accepted => (String::from("accepted"), theme.system_info_color.1),
+                        OfferStatus::AutoAccepted => (String::from("accepted automatically"), theme.system_info_color.1),
+                        OfferStatus::Rejected => (String::from("rejected"), theme.system_error_color.1),
+                        OfferStatus::Expired => (String::from("expired"), theme.date_color),
+                        OfferStatus::Withdrawn => (String::from("cancelled by the sender"), theme.date_color),
+                    };
+                    let offer = match files {
+                        None => format!(" offers the file '{}' ({}): ", file_name, util::format_size(*size)),
+                        Some(1) => format!(" offers '{}' (1 file, {}): ", file_name, util::format_size(*size)),
+                        Some(files) => format!(" offers '{}' ({} files, {}): ", file_name, files, util::format_size(*size)),
+                    };
+                    Spans::from(vec![
+                        Span::styled(date, Style::default().fg(theme.date_color)),
+                        Span::styled(&message.user, Style::default().fg(color)),
+                        Span::styled(offer, Style::default().fg(color)),
+                        Span::styled(answer, Style::default().fg(answer_color)),
+                    ])
+                }
+                MessageType::HistoryEnd => {
+                    let line = "-".repeat((chunk.width as usize).saturating_sub(2));
+                    Spans::from(Span::styled(line, Style::default().fg(theme.date_color)))
                 }
+            };
+            if let Some((_, status)) = message.sent {
+                let (mark, color) = match status {
+                    MessageStatus::Sending => (" ...", theme.date_color),
+                    MessageStatus::Sent => (" ✓", theme.date_color),
+                    MessageStatus::Delivered => (" ✓✓", theme.date_color),
+                    MessageStatus::Failed => (" ✗ not sent", theme.system_error_color.1),
+                };
+                spans.0.push(Span::styled(mark, Style::default().fg(color)));
+            }
+            match message.replayed {
+                true => dim(spans),
+                false => spans,
             }
         })
         .collect::<Vec<_>>();
 
+    let title = match state.room() {
+        Some(room) => format!("Chat Room #{}", room),
+        None => String::from("Chat Room"),
+    };
+
     let messages_panel = Paragraph::new(messages)
         .block(
             Block::default()
                 .borders(Borders::ALL)
-                .title(Span::styled("Chat Room", Style::default().add_modifier(Modifier::BOLD))),
+                .title(Span::styled(title, Style::default().add_modifier(Modifier::BOLD))),
         )
         .style(Style::default().fg(theme.chat_panel_color))
         .alignment(Alignment::Left)
@@ -104,23 +299,41 @@ fn draw_messages_panel(
     frame.render_widget(messages_panel, chunk);
 }
 
+fn dim(spans: Spans) -> Spans {
+    Spans::from(
+        spans.0
+            .into_iter()
+            .map(|span| Span::styled(span.content, span.style.add_modifier(Modifier::DIM)))
+            .collect::<Vec<_>>(),
+    )
+}
+
 fn add_progress_bar<'a>(
     panel_width: u16,
+    label: &str,
     progress: &'a ProgressState,
     theme: &Theme,
 ) -> Vec<Span<'a>> {
-    let color = theme.progress_bar_color;
-    let width = (panel_width - 20) as usize;
+    let (title, color) = match progress {
+        ProgressState::Started(_) => (format!("{}, pending: ", label), theme.progress_bar_color),
+        ProgressState::Working(_, _) => (format!("{}: ", label), theme.progress_bar_color),
+        ProgressState::Completed => (format!("{}, done! ", label), theme.progress_bar_color),
+        ProgressState::Paused(_, _) => (format!("{}, paused: ", label), theme.system_warning_color.1),
+        ProgressState::Cancelled(_, _) => (format!("{}, cancelled: ", label), theme.date_color),
+    };
+    let width = (panel_width as usize).saturating_sub(20 + title.width()).max(10);
 
-    let (title, ui_current, ui_remaining) = match progress {
-        ProgressState::Started(_) => ("Pending: ", 0, width),
-        ProgressState::Working(total, current) => {
+    let (ui_current, ui_remaining) = match progress {
+        ProgressState::Started(_) => (0, width),
+        ProgressState::Working(total, current)
+        | ProgressState::Paused(total, current)
+        | ProgressState::Cancelled(total, current) => {
             let percentage = *current as f64 / *total as f64;
             let ui_current = (percentage * width as f64) as usize;
             let ui_remaining = width - ui_current;
-            ("Sending: ", ui_current, ui_remaining)
+            (ui_current, ui_remaining)
         }
-        ProgressState::Completed => ("Done! ", width, 0),
+        ProgressState::Completed => (width, 0),
     };
 
     let current: String = std::iter::repeat("#").take(ui_current).collect();
@@ -169,11 +382,36 @@ fn draw_input_panel(
         .map(|line| Spans::from(vec![Span::raw(line)]))
         .collect::<Vec<_>>();
 
+    let (connection, connection_color) = match state.connection() {
+        ConnectionStatus::Connecting => (String::from("connecting..."), theme.system_warning_color.1),
+        ConnectionStatus::Online => (String::from("online"), theme.system_info_color.1),
+        ConnectionStatus::Offline(delay) => {
+            (format!("offline, reconnecting in {}s", delay.as_secs()), theme.system_error_color.1)
+        }
+        ConnectionStatus::Closed => (String::from("disconnected"), theme.system_error_color.1),
+    };
+    let mut title = vec![
+        Span::styled("Your message ", Style::default().add_modifier(Modifier::BOLD)),
+        Span::styled(format!("({})", connection), Style::default().fg(connection_color)),
+    ];
+    if let (ConnectionStatus::Online, Some(latency)) = (state.connection(), state.latency()) {
+        let (quality, quality_color) = match latency.as_millis() {
+            0..=99 => ("good", theme.system_info_color.1),
+            100..=299 => ("fair", theme.system_warning_color.1),
+            _ => ("poor", theme.system_error_color.1),
+        };
+        title.push(Span::styled(
+            format!(" {} ms, {} connection", latency.as_millis(), quality),
+            Style::default().fg(quality_color),
+        ));
+    }
+    let title = Spans::from(title);
+
     let input_panel = Paragraph::new(input)
         .block(
             Block::default()
                 .borders(Borders::ALL)
-                .title(Span::styled("Your message", Style::default().add_modifier(Modifier::BOLD))),
+                .title(title),
         )
         .style(Style::default().fg(theme.input_panel_color))
         .alignment(Alignment::Left);
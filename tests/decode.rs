@@ -0,0 +1,180 @@
+use proptest::prelude::*;
+use termchat_wan::message::{
+    check_room_name, Capabilities, Chunk, Credential, DeliveryStatus, HistoryEntry, NetMessage, Receipt, Sealed, MAX_CLIENT_FRAME, MAX_SEALED_LEN,
+    MAX_ROOM_NAME_LEN, MAX_SERVER_FRAME, MAX_STRING_LEN, PROTOCOL_VERSION,
+};
+
+type SealedParts = ([u8; 32], [u8; 12], Vec<u8>);
+
+fn sealed(max_len: usize) -> impl Strategy<Value = SealedParts> {
+    (any::<[u8; 32]>(), any::<[u8; 12]>(), prop::collection::vec(any::<u8>(), 0..max_len))
+}
+
+fn build_sealed((sender_key, nonce, data): SealedParts) -> Sealed {
+    Sealed { sender_key, nonce, data }
+}
+
+fn encode(message: &NetMessage) -> Vec<u8> {
+    bincode::serialize(message).unwrap()
+}
+
+/// Frames of valid messages, the strategies only produce `Debug` values.
+fn frame() -> impl Strategy<Value = Vec<u8>> {
+    let name = || ".{0,40}";
+    prop_oneof![
+        name().prop_map(|user| encode(&NetMessage::Hi(user))),
+        any::<u64>().prop_map(|timestamp| encode(&NetMessage::BBB(timestamp))),
+        (name(), prop::option::of(name())).prop_map(|(user, password)| {
+            let credential = password.map_or(Credential::Anonymous, Credential::Password);
+            encode(&NetMessage::Logon(user, credential))
+        }),
+        (any::<u64>(), ".{0,200}").prop_map(|(id, text)| encode(&NetMessage::SendMessage(id, text))),
+        (any::<u64>(), name(), sealed(1024))
+            .prop_map(|(id, to, sealed)| encode(&NetMessage::SendWhisper(id, to, build_sealed(sealed)))),
+        (name(), any::<u64>(), 0..5, any::<u64>(), sealed(1024)).prop_map(|(to, transfer_id, kind, offset, sealed)| {
+            let sealed = build_sealed(sealed);
+            let chunk = match kind {
+                0 => Chunk::Offer(sealed),
+                1 => Chunk::Data(offset, sealed),
+                2 => Chunk::End(sealed),
+                3 => Chunk::Cancel,
+                _ => Chunk::Dropped,
+            };
+            encode(&NetMessage::SendData(to, transfer_id, chunk))
+        }),
+        (name(), any::<u64>(), 0..4, any::<u64>()).prop_map(|(to, transfer_id, kind, offset)| {
+            let receipt = match kind {
+                0 => Receipt::Completed,
+                1 => Receipt::Received(offset),
+                2 => Receipt::Held(offset),
+                _ => Receipt::Released(offset),
+            };
+            encode(&NetMessage::SendReceipt(to, transfer_id, receipt))
+        }),
+        prop::option::of(any::<u64>()).prop_map(|since| encode(&NetMessage::RequestHistory(since))),
+        (name(), prop::collection::vec((any::<u64>(), any::<i64>(), name(), ".{0,100}"), 0..20)).prop_map(
+            |(room, entries)| {
+                let entries = entries
+                    .into_iter()
+                    .map(|(id, date, user, content)| HistoryEntry { id, date, user, content })
+                    .collect();
+                encode(&NetMessage::History(room, entries))
+            }
+        ),
+        prop::collection::vec(name(), 0..20).prop_map(|users| encode(&NetMessage::Roster(users))),
+        (name(), prop::option::of(any::<u64>()), name()).prop_map(|(user, id, reason)| {
+            encode(&NetMessage::Delivery(user, id, DeliveryStatus::Rejected(reason)))
+        }),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn arbitrary_bytes_never_panic(data in prop::collection::vec(any::<u8>(), 0..2048)) {
+        let _ = NetMessage::decode(&data, MAX_CLIENT_FRAME);
+        let _ = NetMessage::decode(&data, MAX_SERVER_FRAME);
+    }
+
+    #[test]
+    fn valid_messages_round_trip(data in frame()) {
+        let decoded = NetMessage::decode(&data, MAX_SERVER_FRAME).unwrap();
+        prop_assert_eq!(encode(&decoded), data);
+    }
+
+    #[test]
+    fn corrupted_frames_never_panic(
+        mut data in frame(),
+        flips in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..8),
+        cut in any::<prop::sample::Index>(),
+    ) {
+        for (index, byte) in flips {
+            let index = index.index(data.len());
+            data[index] ^= byte;
+        }
+        let _ = NetMessage::decode(&data, MAX_CLIENT_FRAME);
+        let _ = NetMessage::decode(&data[..cut.index(data.len() + 1)], MAX_CLIENT_FRAME);
+    }
+
+    #[test]
+    fn huge_lengths_are_rejected(length in (MAX_CLIENT_FRAME..u64::MAX)) {
+        // A SendMessage whose text claims to be longer than the frame.
+        let mut data = encode(&NetMessage::SendMessage(1, String::new()));
+        let prefix = data.len() - 8;
+        data[prefix..].copy_from_slice(&length.to_le_bytes());
+        prop_assert!(NetMessage::decode(&data, MAX_CLIENT_FRAME).is_err());
+    }
+}
+
+#[test]
+fn strings_over_the_limit_are_rejected() {
+    let text = "a".repeat(MAX_STRING_LEN);
+    assert!(NetMessage::decode(&encode(&NetMessage::SendMessage(1, text.clone())), MAX_CLIENT_FRAME).is_ok());
+    let text = text + "a";
+    assert!(NetMessage::decode(&encode(&NetMessage::SendMessage(1, text.clone())), MAX_CLIENT_FRAME).is_err());
+    let roster = NetMessage::Roster(vec![String::from("alice"), text]);
+    assert!(NetMessage::decode(&encode(&roster), MAX_SERVER_FRAME).is_err());
+}
+
+#[test]
+fn chunks_over_the_limit_are_rejected() {
+    let chunk = |len| {
+        let sealed = Sealed { sender_key: [0; 32], nonce: [0; 12], data: vec![0; len] };
+        encode(&NetMessage::SendData(String::from("bob"), 1, Chunk::Data(0, sealed)))
+    };
+    assert!(NetMessage::decode(&chunk(MAX_SEALED_LEN), MAX_CLIENT_FRAME).is_ok());
+    assert!(NetMessage::decode(&chunk(MAX_SEALED_LEN + 1), MAX_CLIENT_FRAME).is_err());
+}
+
+#[test]
+fn frames_over_the_limit_are_rejected() {
+    let data = encode(&NetMessage::SendMessage(1, "a".repeat(100)));
+    assert!(NetMessage::decode(&data, data.len() as u64).is_ok());
+    assert!(NetMessage::decode(&data, data.len() as u64 - 1).is_err());
+}
+
+#[test]
+fn trailing_bytes_are_rejected() {
+    let mut data = encode(&NetMessage::LeaveRoom);
+    data.push(0);
+    assert!(NetMessage::decode(&data, MAX_CLIENT_FRAME).is_err());
+}
+
+#[test]
+fn handshake_encoding_never_changes() {
+    // Any version of the server must understand the handshake of any client, and the other way around.
+    let handshake = encode(&NetMessage::Handshake(7, Capabilities::ROOMS));
+    assert_eq!(handshake, [0, 0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0]);
+    let incompatible = encode(&NetMessage::Incompatible(String::from("a")));
+    assert_eq!(incompatible, [1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, b'a']);
+    assert!(NetMessage::decode(&encode(&NetMessage::Handshake(PROTOCOL_VERSION, Capabilities::ALL)), MAX_CLIENT_FRAME).is_ok());
+}
+
+#[test]
+fn compressed_frames_are_decoded() {
+    let text = "hello ".repeat(500);
+    let message = NetMessage::SendMessage(1, text.clone());
+    let frame = message.encode(true);
+    assert!(frame.len() < encode(&message).len() / 4);
+    assert!(matches!(NetMessage::decode(&frame, MAX_CLIENT_FRAME).unwrap(), NetMessage::SendMessage(1, t) if t == text));
+    // Sealed data does not compress, it is sent as it is.
+    let sealed = Sealed { sender_key: [0; 32], nonce: [0; 12], data: vec![0; 1000] };
+    let whisper = NetMessage::SendWhisper(1, String::from("bob"), sealed);
+    assert_eq!(whisper.encode(true), encode(&whisper));
+}
+
+#[test]
+fn compressed_frames_over_the_limit_are_rejected() {
+    let frame = NetMessage::SendMessage(1, "a".repeat(MAX_STRING_LEN)).encode(true);
+    assert!(frame.len() < 1000);
+    assert!(NetMessage::decode(&frame, 1000).is_err());
+}
+
+#[test]
+fn room_names_are_checked() {
+    assert!(check_room_name("lobby").is_ok());
+    assert!(check_room_name(&"r".repeat(MAX_ROOM_NAME_LEN)).is_ok());
+    assert!(check_room_name(&"r".repeat(MAX_ROOM_NAME_LEN + 1)).is_err());
+    assert!(check_room_name("").is_err());
+    assert!(check_room_name("two words").is_err());
+    assert!(check_room_name("bell\u{7}").is_err());
+}
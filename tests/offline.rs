@@ -0,0 +1,140 @@
+use message_io::util::encoding::{self, Decoder, MAX_ENCODED_SIZE};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use termchat_wan::message::{
+    Capabilities, Chunk, Credential, DeliveryStatus, NetMessage, Receipt, Sealed, MAX_SERVER_FRAME, PROTOCOL_VERSION,
+};
+
+/// Server binary listening on a free port, killed when dropped.
+struct Server {
+    process: Child,
+    port: u16,
+    dir: PathBuf,
+}
+
+impl Server {
+    fn start(name: &str) -> Server {
+        let dir = std::env::temp_dir().join(format!("termchat-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let process = Command::new(env!("CARGO_BIN_EXE_server"))
+            .args(["--port", &port.to_string(), "--offline-files", "100000"])
+            .arg("--bans")
+            .arg(dir.join("bans.toml"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        Server { process, port, dir }
+    }
+
+    /// Logs on as the user and publishes its key, once the server accepts connections.
+    fn logon(&self, user: &str) -> Client {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let socket = loop {
+            match TcpStream::connect(("127.0.0.1", self.port)) {
+                Ok(socket) => break socket,
+                Err(e) if Instant::now() > deadline => panic!("the server is not listening: {}", e),
+                Err(_) => std::thread::sleep(Duration::from_millis(20)),
+            }
+        };
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let mut client = Client { socket, decoder: Decoder::default(), received: Vec::new() };
+        client.send(&NetMessage::Handshake(PROTOCOL_VERSION, Capabilities::default()));
+        client.send(&NetMessage::Logon(user.to_string(), Credential::Anonymous));
+        client.receive(|message| matches!(message, NetMessage::LogonAccepted(_)));
+        // The server only keeps messages for the users it knows the key of.
+        client.send(&NetMessage::PublishKey([1; 32]));
+        client
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Connection speaking the framing of the clients.
+struct Client {
+    socket: TcpStream,
+    decoder: Decoder,
+    received: Vec<NetMessage>,
+}
+
+impl Client {
+    fn send(&mut self, message: &NetMessage) {
+        let data = message.encode(false);
+        let mut buf = [0; MAX_ENCODED_SIZE];
+        self.socket.write_all(encoding::encode_size(&data, &mut buf)).unwrap();
+        self.socket.write_all(&data).unwrap();
+    }
+
+    /// Waits for a message, the other ones received meanwhile are skipped.
+    fn receive(&mut self, expected: impl Fn(&NetMessage) -> bool) -> NetMessage {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(index) = self.received.iter().position(&expected) {
+                self.received.drain(..index);
+                return self.received.remove(0)
+            }
+            assert!(Instant::now() < deadline, "the expected message was not received");
+            let mut data = [0; 65536];
+            let len = match self.socket.read(&mut data) {
+                Ok(0) => panic!("the server closed the connection"),
+                Ok(len) => len,
+                Err(_) => continue,
+            };
+            let received = &mut self.received;
+            self.decoder.decode(&data[..len], |frame| {
+                received.push(NetMessage::decode(frame, MAX_SERVER_FRAME).unwrap());
+            });
+        }
+    }
+}
+
+fn sealed(data: &[u8]) -> Sealed {
+    Sealed { sender_key: [0; 32], nonce: [0; 12], data: data.to_vec() }
+}
+
+#[test]
+fn files_wait_for_an_offline_recipient() {
+    let server = Server::start("offline");
+    let bob = server.logon("bob");
+    let mut alice = server.logon("alice");
+    drop(bob);
+    alice.receive(|message| matches!(message, NetMessage::Bye(user) if user == "bob"));
+
+    // The sender is told the offer is kept, so it waits for the answer.
+    alice.send(&NetMessage::SendData(String::from("bob"), 7, Chunk::Offer(sealed(b"manifest"))));
+    let delivery = alice.receive(|message| matches!(message, NetMessage::Delivery(..)));
+    assert!(matches!(delivery, NetMessage::Delivery(user, None, DeliveryStatus::Queued) if user == "bob"));
+    let receipt = alice.receive(|message| matches!(message, NetMessage::UserReceipt(..)));
+    assert!(matches!(receipt, NetMessage::UserReceipt(user, 7, Receipt::Queued(0)) if user == "bob"));
+
+    // Once online, the recipient answers the offer and the data goes to it.
+    let mut bob = server.logon("bob");
+    let offer = bob.receive(|message| matches!(message, NetMessage::UserData(..)));
+    assert!(matches!(offer, NetMessage::UserData(from, _, 7, Chunk::Offer(sealed)) if from == "alice" && sealed.data == b"manifest"));
+    bob.send(&NetMessage::SendReceipt(String::from("alice"), 7, Receipt::Accepted(Capabilities::ALL)));
+    let receipt = alice.receive(|message| matches!(message, NetMessage::UserReceipt(..)));
+    assert!(matches!(receipt, NetMessage::UserReceipt(user, 7, Receipt::Accepted(_)) if user == "bob"));
+    alice.send(&NetMessage::SendData(String::from("bob"), 7, Chunk::Data(0, sealed(b"first"))));
+    let data = bob.receive(|message| matches!(message, NetMessage::UserData(..)));
+    assert!(matches!(data, NetMessage::UserData(_, _, 7, Chunk::Data(0, sealed)) if sealed.data == b"first"));
+
+    // The data sent while the recipient is offline again is kept for it.
+    drop(bob);
+    alice.receive(|message| matches!(message, NetMessage::Bye(user) if user == "bob"));
+    alice.send(&NetMessage::SendData(String::from("bob"), 7, Chunk::Data(5, sealed(b"second"))));
+    let receipt = alice.receive(|message| matches!(message, NetMessage::UserReceipt(..)));
+    assert!(matches!(receipt, NetMessage::UserReceipt(user, 7, Receipt::Queued(5)) if user == "bob"));
+    let mut bob = server.logon("bob");
+    let data = bob.receive(|message| matches!(message, NetMessage::UserData(..)));
+    assert!(matches!(data, NetMessage::UserData(_, _, 7, Chunk::Data(5, sealed)) if sealed.data == b"second"));
+}
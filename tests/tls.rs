@@ -0,0 +1,93 @@
+use rustls::Certificate;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use termchat_wan::message::{NetMessage, MAX_CLIENT_FRAME};
+use termchat_wan::tls::{self, Acceptor, Trust};
+
+/// Self-signed certificate for localhost, as the README generates it, written in a temporary directory.
+struct Certificates {
+    dir: PathBuf,
+    der: Vec<u8>,
+}
+
+impl Certificates {
+    fn generate(name: &str) -> Certificates {
+        let dir = std::env::temp_dir().join(format!("termchat-test-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let certificate = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        std::fs::write(dir.join("cert.pem"), certificate.serialize_pem().unwrap()).unwrap();
+        std::fs::write(dir.join("key.pem"), certificate.serialize_private_key_pem()).unwrap();
+        // Each serialization is signed again, the fingerprint is the one of the written file.
+        let der = tls::load_certificates(&dir.join("cert.pem")).unwrap().remove(0).0;
+        Certificates { der, dir }
+    }
+
+    fn cert(&self) -> PathBuf {
+        self.dir.join("cert.pem")
+    }
+
+    fn key(&self) -> PathBuf {
+        self.dir.join("key.pem")
+    }
+}
+
+impl Drop for Certificates {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Starts an acceptor in front of a plain listener, like the server does.
+fn spawn_acceptor(certificates: &Certificates) -> (Acceptor, SocketAddr, TcpListener) {
+    let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+    let public_addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let config = tls::server_config(&certificates.cert(), &certificates.key()).unwrap();
+    let acceptor = Acceptor::spawn(public_addr, backend.local_addr().unwrap(), config).unwrap();
+    (acceptor, public_addr, backend)
+}
+
+fn read_message(socket: &mut TcpStream, len: usize) -> NetMessage {
+    let mut data = vec![0; len];
+    socket.read_exact(&mut data).unwrap();
+    NetMessage::decode(&data, MAX_CLIENT_FRAME).unwrap()
+}
+
+/// Sends a frame from the client to the backend through the tunnels, and back.
+fn round_trip(certificates: &Certificates, trust: &Trust) {
+    let (_acceptor, public_addr, backend) = spawn_acceptor(certificates);
+    let local_addr = tls::connect(public_addr, "localhost", trust).unwrap();
+    let mut client = TcpStream::connect(local_addr).unwrap();
+    let (mut server, _) = backend.accept().unwrap();
+
+    let frame = NetMessage::SendMessage(1, String::from("hello through tls")).encode(false);
+    client.write_all(&frame).unwrap();
+    assert!(matches!(read_message(&mut server, frame.len()), NetMessage::SendMessage(1, text) if text == "hello through tls"));
+    server.write_all(&frame).unwrap();
+    assert!(matches!(read_message(&mut client, frame.len()), NetMessage::SendMessage(1, _)));
+}
+
+#[test]
+fn frames_go_through_with_a_pinned_fingerprint() {
+    let certificates = Certificates::generate("tls-fingerprint");
+    let fingerprint = tls::fingerprint(&Certificate(certificates.der.clone()));
+    round_trip(&certificates, &Trust::Fingerprint(fingerprint.to_uppercase()));
+}
+
+#[test]
+fn frames_go_through_with_a_ca_file() {
+    let certificates = Certificates::generate("tls-ca");
+    round_trip(&certificates, &Trust::CaFile(certificates.cert()));
+}
+
+#[test]
+fn other_certificates_are_refused() {
+    let certificates = Certificates::generate("tls-refused");
+    let other = Certificates::generate("tls-other");
+    let (_acceptor, public_addr, _backend) = spawn_acceptor(&certificates);
+    let wrong_fingerprint = tls::fingerprint(&Certificate(other.der.clone()));
+    assert!(tls::connect(public_addr, "localhost", &Trust::Fingerprint(wrong_fingerprint)).is_err());
+    assert!(tls::connect(public_addr, "localhost", &Trust::CaFile(other.cert())).is_err());
+    // The certificate is only valid for localhost.
+    assert!(tls::connect(public_addr, "example.com", &Trust::CaFile(certificates.cert())).is_err());
+}
@@ -0,0 +1,197 @@
+use termchat_wan::compression::{unpack_chunk, ChunkPacker};
+use termchat_wan::message::MAX_SEALED_LEN;
+use termchat_wan::transfer::{sanitize_file_name, unique_path, Entry, FileDigest, IncomingFile, Manifest, OutgoingFiles};
+
+fn download_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("termchat-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+fn digest_of(data: &[u8], dir: &std::path::Path) -> FileDigest {
+    let path = dir.join("source");
+    std::fs::create_dir_all(dir).unwrap();
+    std::fs::write(&path, data).unwrap();
+    let digest = FileDigest::of_file(&mut std::fs::File::open(&path).unwrap()).unwrap();
+    std::fs::remove_file(path).unwrap();
+    digest
+}
+
+fn single_file(name: &str, digest: FileDigest) -> Manifest {
+    let entry = Entry { path: vec![name.to_string()], directory: false, size: digest.size, mode: 0o644 };
+    Manifest { digest, entries: vec![entry] }
+}
+
+#[test]
+fn file_names_can_not_leave_the_directory() {
+    assert_eq!(sanitize_file_name("../../.bashrc"), "_.._.bashrc");
+    assert_eq!(sanitize_file_name("/etc/passwd"), "_etc_passwd");
+    assert_eq!(sanitize_file_name("..\\windows\\system.ini"), "_windows_system.ini");
+    assert_eq!(sanitize_file_name(".."), "file");
+    assert_eq!(sanitize_file_name(""), "file");
+    assert_eq!(sanitize_file_name(".hidden"), "hidden");
+}
+
+#[test]
+fn file_names_are_cleaned() {
+    assert_eq!(sanitize_file_name("report 2024.pdf"), "report 2024.pdf");
+    assert_eq!(sanitize_file_name("a\u{1b}[2Jb\n.txt"), "a_[2Jb_.txt");
+    assert_eq!(sanitize_file_name("what?.txt"), "what_.txt");
+    assert_eq!(sanitize_file_name("nul.txt"), "_nul.txt");
+    assert_eq!(sanitize_file_name("COM1"), "_COM1");
+    assert_eq!(sanitize_file_name("console.log"), "console.log");
+    assert!(sanitize_file_name(&"é".repeat(300)).len() <= 255);
+}
+
+#[test]
+fn existing_files_are_not_replaced() {
+    let dir = download_dir("unique");
+    std::fs::create_dir_all(&dir).unwrap();
+    assert_eq!(unique_path(&dir, "notes.txt"), dir.join("notes.txt"));
+    std::fs::write(dir.join("notes.txt"), "").unwrap();
+    assert_eq!(unique_path(&dir, "notes.txt"), dir.join("notes (1).txt"));
+    std::fs::write(dir.join("notes (1).txt"), "").unwrap();
+    assert_eq!(unique_path(&dir, "notes.txt"), dir.join("notes (2).txt"));
+    std::fs::write(dir.join("README"), "").unwrap();
+    assert_eq!(unique_path(&dir, "README"), dir.join("README (1)"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn files_are_renamed_once_completed() {
+    let dir = download_dir("complete");
+    let data = b"some data of the file";
+    let digest = digest_of(data, &dir);
+    std::fs::write(dir.join("data.bin"), "previous").unwrap();
+
+    let mut file = IncomingFile::create(&dir, "alice", 1, single_file("../data.bin", digest.clone())).unwrap();
+    file.write(0, &data[..10]).unwrap();
+    assert!(!file.verify(&digest).unwrap());
+    file.write(10, &data[10..]).unwrap();
+    assert!(file.verify(&digest).unwrap());
+    let path = file.complete().unwrap().remove(0);
+
+    assert_eq!(path, dir.join("_data.bin"));
+    assert_eq!(std::fs::read(&path).unwrap(), data);
+    assert_eq!(std::fs::read(dir.join("data.bin")).unwrap(), b"previous");
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn partial_files_are_removed() {
+    let dir = download_dir("partial");
+    let data = b"some data of the file";
+    let digest = digest_of(data, &dir);
+
+    let mut file = IncomingFile::create(&dir, "alice", 1, single_file("data.bin", digest.clone())).unwrap();
+    file.write(0, &data[..10]).unwrap();
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    assert!(file.write(10, &[0; 100]).is_err());
+    // An offset that overflows is past the offered size too.
+    assert!(file.write(u64::MAX - 1, &[0; 10]).is_err());
+    // Another transfer of the same file has its own partial file.
+    let mut other = IncomingFile::create(&dir, "alice", 2, single_file("data.bin", digest)).unwrap();
+    other.write(0, &data[..5]).unwrap();
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    drop(file);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    drop(other);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn directories_keep_their_layout() {
+    let source = download_dir("layout-source");
+    std::fs::create_dir_all(source.join("docs/empty")).unwrap();
+    std::fs::create_dir_all(source.join("docs/sub")).unwrap();
+    std::fs::write(source.join("docs/a.txt"), "first file").unwrap();
+    std::fs::write(source.join("docs/sub/b.txt"), "second").unwrap();
+    std::fs::write(source.join("docs/sub/nothing"), "").unwrap();
+    std::fs::write(source.join("notes.txt"), "notes").unwrap();
+
+    let mut files = OutgoingFiles::collect(&[source.join("docs"), source.join("notes.txt")]).unwrap();
+    assert_eq!(files.name(), "docs and 1 more");
+    assert_eq!(files.size(), 21);
+    // Chunks over the end of a file continue with the next one.
+    assert_eq!(files.read(5, 10).unwrap(), b" filesecon");
+    // Hashed a part at a time, also over the end of a file.
+    assert!(files.hash(8).unwrap().is_none());
+    assert!(files.hash(8).unwrap().is_none());
+    let manifest = Manifest::from_bytes(&files.hash(8).unwrap().unwrap().to_bytes()).unwrap();
+    assert_eq!(manifest.files(), 4);
+    assert_eq!(manifest.name(), files.name());
+
+    let dir = download_dir("layout");
+    std::fs::create_dir_all(dir.join("docs")).unwrap();
+    let mut incoming = IncomingFile::create(&dir, "alice", 1, manifest.clone()).unwrap();
+    incoming.write(0, &files.read(0, 21).unwrap()).unwrap();
+    assert!(incoming.verify(&manifest.digest).unwrap());
+    let paths = incoming.complete().unwrap();
+
+    assert_eq!(paths, vec![dir.join("docs (1)"), dir.join("notes.txt")]);
+    assert_eq!(std::fs::read(dir.join("docs (1)/a.txt")).unwrap(), b"first file");
+    assert_eq!(std::fs::read(dir.join("docs (1)/sub/b.txt")).unwrap(), b"second");
+    assert_eq!(std::fs::read(dir.join("docs (1)/sub/nothing")).unwrap(), b"");
+    assert!(dir.join("docs (1)/empty").is_dir());
+    assert_eq!(std::fs::read(dir.join("notes.txt")).unwrap(), b"notes");
+    // Only the directories and files sent, without the partial file.
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+    std::fs::remove_dir_all(source).unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn offers_must_match_their_files() {
+    let digest = FileDigest { size: 10, hash: [0; 32] };
+    let mut manifest = single_file("data.bin", digest);
+    assert!(Manifest::from_bytes(&manifest.to_bytes()).is_ok());
+    manifest.entries[0].size = 11;
+    assert!(Manifest::from_bytes(&manifest.to_bytes()).is_err());
+    manifest.entries[0].size = 10;
+    // The name of the transfer is the file or directory at the root.
+    manifest.entries[0].path.insert(0, "dir".to_string());
+    assert!(Manifest::from_bytes(&manifest.to_bytes()).is_err());
+    manifest.entries[0].path.clear();
+    assert!(Manifest::from_bytes(&manifest.to_bytes()).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn permissions_are_kept() {
+    use std::os::unix::fs::PermissionsExt;
+    let source = download_dir("mode-source");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::write(source.join("run.sh"), "#!/bin/sh").unwrap();
+    std::fs::set_permissions(source.join("run.sh"), std::fs::Permissions::from_mode(0o4755)).unwrap();
+
+    let mut files = OutgoingFiles::collect(&[source.join("run.sh")]).unwrap();
+    let manifest = files.manifest().unwrap();
+    let dir = download_dir("mode");
+    let mut incoming = IncomingFile::create(&dir, "alice", 1, manifest).unwrap();
+    incoming.write(0, &files.read(0, 9).unwrap()).unwrap();
+    let path = incoming.complete().unwrap().remove(0);
+
+    // Without the setuid bit.
+    assert_eq!(std::fs::metadata(path).unwrap().permissions().mode() & 0o7777, 0o755);
+    std::fs::remove_dir_all(source).unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn chunks_are_compressed_unless_already_compressed() {
+    let text = b"some text of a file ".repeat(1000);
+    let mut packer = ChunkPacker::new(true);
+    let packed = packer.pack(&text);
+    assert!(packed.len() < text.len() / 4);
+    assert_eq!(unpack_chunk(&packed, MAX_SEALED_LEN).unwrap(), text);
+    assert!(unpack_chunk(&packed, 100).is_err());
+
+    // A gzip file is sent as it is, and the next chunks too for a while.
+    let gzip = [&b"\x1f\x8b"[..], &text].concat();
+    assert_eq!(packer.pack(&gzip).len(), gzip.len() + 1);
+    assert_eq!(packer.pack(&text).len(), text.len() + 1);
+    // Without the capability of the recipient.
+    assert_eq!(ChunkPacker::new(false).pack(&text).len(), text.len() + 1);
+}